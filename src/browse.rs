@@ -0,0 +1,339 @@
+use crate::error::CbltError;
+use crate::response::respond;
+use bytes::BytesMut;
+use http::header::{ACCEPT, CONTENT_TYPE, VARY};
+use http::{Request, Response, StatusCode};
+use std::cmp::Ordering;
+use std::fmt::Write;
+use std::path::Path;
+use std::time::SystemTime;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+#[derive(Debug, Clone)]
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SortKey {
+    Name,
+    Size,
+    Time,
+}
+
+impl SortKey {
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    fn as_str(&self) -> &'static str {
+        match self {
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::Time => "time",
+        }
+    }
+}
+
+/// Lists a directory as HTML, or as JSON for `Accept: application/json`.
+/// Sorting is selected with `?sort=name|size|time&order=asc|desc`.
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn browse_directive<S>(
+    dir_path: &Path,
+    request: &Request<BytesMut>,
+    socket: &mut S,
+) -> Result<StatusCode, CbltError>
+where
+    S: AsyncWriteExt + Unpin,
+{
+    let mut entries = read_entries(dir_path).await?;
+    let (sort_key, descending) = sort_params(request.uri().query().unwrap_or(""));
+    sort_entries(&mut entries, sort_key, descending);
+
+    let path = request.uri().path();
+    let wants_json = request
+        .headers()
+        .get(ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("application/json"));
+    let (body, content_type) = if wants_json {
+        (render_json(path, &entries), "application/json")
+    } else {
+        (
+            render_html(path, &entries, sort_key, descending),
+            "text/html; charset=utf-8",
+        )
+    };
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, content_type)
+        .header(VARY, "Accept")
+        .body(BytesMut::from(body.as_bytes()))?;
+    respond(socket, request, response).await?;
+    Ok(StatusCode::OK)
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn read_entries(dir_path: &Path) -> Result<Vec<Entry>, CbltError> {
+    let mut entries = Vec::new();
+    let mut read_dir = fs::read_dir(dir_path).await?;
+    while let Some(dir_entry) = read_dir.next_entry().await? {
+        // Follows symlinks, broken ones are left out
+        let Ok(metadata) = fs::metadata(dir_entry.path()).await else {
+            continue;
+        };
+        entries.push(Entry {
+            name: dir_entry.file_name().to_string_lossy().into_owned(),
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata.modified().ok(),
+        });
+    }
+    Ok(entries)
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn sort_params(query: &str) -> (SortKey, bool) {
+    let mut sort_key = SortKey::Name;
+    let mut descending = false;
+    for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+        match (key, value) {
+            ("sort", "name") => sort_key = SortKey::Name,
+            ("sort", "size") => sort_key = SortKey::Size,
+            ("sort", "time") => sort_key = SortKey::Time,
+            ("order", "desc") => descending = true,
+            ("order", "asc") => descending = false,
+            _ => {}
+        }
+    }
+    (sort_key, descending)
+}
+
+/// Directories always come first, the order applies within both groups
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn sort_entries(entries: &mut [Entry], sort_key: SortKey, descending: bool) {
+    entries.sort_by(|a, b| {
+        let ordering = match sort_key {
+            SortKey::Name => Ordering::Equal,
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Time => a.modified.cmp(&b.modified),
+        }
+        .then_with(|| a.name.cmp(&b.name));
+        let ordering = if descending {
+            ordering.reverse()
+        } else {
+            ordering
+        };
+        b.is_dir.cmp(&a.is_dir).then(ordering)
+    });
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn render_html(path: &str, entries: &[Entry], sort_key: SortKey, descending: bool) -> String {
+    let title = html_escape(path);
+    let mut html = String::with_capacity(512 + entries.len() * 160);
+    let _ = write!(
+        html,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Index of {title}</title>\n\
+         <style>body{{font-family:sans-serif}}td,th{{padding:2px 12px;text-align:left}}</style>\n\
+         </head>\n<body>\n<h1>Index of {title}</h1>\n<table>\n<tr>"
+    );
+    for (key, label) in [
+        (SortKey::Name, "Name"),
+        (SortKey::Size, "Size"),
+        (SortKey::Time, "Modified"),
+    ] {
+        // Clicking the active column flips the order
+        let order = if key == sort_key && !descending {
+            "desc"
+        } else {
+            "asc"
+        };
+        let _ = write!(
+            html,
+            "<th><a href=\"?sort={}&amp;order={}\">{}</a></th>",
+            key.as_str(),
+            order,
+            label
+        );
+    }
+    html.push_str("</tr>\n");
+
+    if let Some((parent, _)) = path.trim_end_matches('/').rsplit_once('/') {
+        let _ = writeln!(
+            html,
+            "<tr><td><a href=\"{}/\">../</a></td><td></td><td></td></tr>",
+            html_escape(parent)
+        );
+    }
+    for entry in entries {
+        let suffix = if entry.is_dir { "/" } else { "" };
+        let size = if entry.is_dir {
+            "-".to_string()
+        } else {
+            entry.size.to_string()
+        };
+        let _ = writeln!(
+            html,
+            "<tr><td><a href=\"{}{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>",
+            html_escape(&entry_base(path)),
+            percent_encode(&entry.name),
+            suffix,
+            html_escape(&entry.name),
+            suffix,
+            size,
+            format_time(entry.modified)
+        );
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn render_json(path: &str, entries: &[Entry]) -> String {
+    let mut json = String::with_capacity(2 + entries.len() * 128);
+    json.push('[');
+    for (i, entry) in entries.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let suffix = if entry.is_dir { "/" } else { "" };
+        let _ = write!(
+            json,
+            "{{\"name\":\"{}\",\"url\":\"{}{}{}\",\"is_dir\":{},\"size\":{},\"modified\":\"{}\"}}",
+            json_escape(&entry.name),
+            json_escape(&entry_base(path)),
+            json_escape(&percent_encode(&entry.name)),
+            suffix,
+            entry.is_dir,
+            entry.size,
+            format_time(entry.modified)
+        );
+    }
+    json.push(']');
+    json
+}
+
+/// Links are absolute, so they also work when the directory was requested without a trailing slash
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn entry_base(path: &str) -> String {
+    if path.ends_with('/') {
+        path.to_string()
+    } else {
+        format!("{}/", path)
+    }
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn format_time(time: Option<SystemTime>) -> String {
+    time.map(|t| humantime::format_rfc3339_seconds(t).to_string())
+        .unwrap_or_default()
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn html_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn json_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => {
+                let _ = write!(encoded, "%{:02X}", byte);
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::browse::{render_html, render_json, sort_entries, sort_params, Entry, SortKey};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn entries() -> Vec<Entry> {
+        let entry = |name: &str, is_dir: bool, size: u64, secs: u64| Entry {
+            name: name.to_string(),
+            is_dir,
+            size,
+            modified: Some(UNIX_EPOCH + Duration::from_secs(secs)),
+        };
+        vec![
+            entry("b.txt", false, 10, 300),
+            entry("docs", true, 0, 100),
+            entry("a <1>.txt", false, 30, 200),
+        ]
+    }
+
+    fn names(entries: &[Entry]) -> Vec<&str> {
+        entries.iter().map(|e| e.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_sort() {
+        assert_eq!(sort_params(""), (SortKey::Name, false));
+        assert_eq!(sort_params("sort=size&order=desc"), (SortKey::Size, true));
+
+        let mut list = entries();
+        sort_entries(&mut list, SortKey::Name, false);
+        assert_eq!(names(&list), vec!["docs", "a <1>.txt", "b.txt"]);
+        sort_entries(&mut list, SortKey::Size, true);
+        assert_eq!(names(&list), vec!["docs", "a <1>.txt", "b.txt"]);
+        sort_entries(&mut list, SortKey::Time, true);
+        assert_eq!(names(&list), vec!["docs", "b.txt", "a <1>.txt"]);
+    }
+
+    #[test]
+    fn test_render() {
+        let list = entries();
+        let html = render_html("/files", &list, SortKey::Name, false);
+        assert!(html.contains("<a href=\"/files/a%20%3C1%3E.txt\">a &lt;1&gt;.txt</a>"));
+        assert!(html.contains("<a href=\"/files/docs/\">docs/</a>"));
+        assert!(html.contains("<a href=\"?sort=name&amp;order=desc\">Name</a>"));
+        assert!(html.contains("<a href=\"/\">../</a>"));
+        assert!(!render_html("/", &list, SortKey::Name, false).contains("../"));
+
+        let json = render_json("/", &list[..1]);
+        assert_eq!(
+            json,
+            "[{\"name\":\"b.txt\",\"url\":\"/b.txt\",\"is_dir\":false,\"size\":10,\
+             \"modified\":\"1970-01-01T00:05:00Z\"}]"
+        );
+    }
+}
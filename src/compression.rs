@@ -0,0 +1,188 @@
+use crate::config::{EncodeOptions, Encoding};
+use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder, ZstdEncoder};
+use async_compression::Level;
+use bytes::BytesMut;
+use http::header::ACCEPT_ENCODING;
+use http::Request;
+use tokio::io::{AsyncBufRead, AsyncRead};
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+/// Formats that are compressed already and would only get bigger
+const COMPRESSED_TYPES: [&str; 14] = [
+    "application/gzip",
+    "application/x-gzip",
+    "application/zip",
+    "application/zstd",
+    "application/x-bzip2",
+    "application/x-xz",
+    "application/x-7z-compressed",
+    "application/x-rar-compressed",
+    "application/vnd.rar",
+    "application/pdf",
+    "application/octet-stream",
+    "font/woff",
+    "font/woff2",
+    "application/font-woff",
+];
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn is_compressible(mime_type: &str) -> bool {
+    let essence = mime_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    if essence == "image/svg+xml" {
+        return true;
+    }
+    !(essence.starts_with("image/")
+        || essence.starts_with("video/")
+        || essence.starts_with("audio/")
+        || COMPRESSED_TYPES.contains(&essence.as_str()))
+}
+
+/// Picks the encoding with the highest q-value in Accept-Encoding, ties go to the host order
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn negotiate(
+    request: &Request<BytesMut>,
+    options: &EncodeOptions,
+) -> Option<(Encoding, Option<i32>)> {
+    let best = *preferred_encodings(request, options.encodings.iter().map(|(e, _)| *e)).first()?;
+    options.encodings.iter().find(|(e, _)| *e == best).copied()
+}
+
+/// Encodings the client accepts, best q-value first, ties keep the given order
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn preferred_encodings(
+    request: &Request<BytesMut>,
+    encodings: impl IntoIterator<Item = Encoding>,
+) -> Vec<Encoding> {
+    let Some(accept_encoding) = request
+        .headers()
+        .get(ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return Vec::new();
+    };
+
+    let mut wildcard = None;
+    let mut accepted = Vec::new();
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';');
+        let name = params.next().unwrap_or("").trim().to_ascii_lowercase();
+        let q = params
+            .find_map(|param| {
+                let (key, value) = param.split_once('=')?;
+                key.trim()
+                    .eq_ignore_ascii_case("q")
+                    .then(|| value.trim().parse::<f32>().unwrap_or(0.0))
+            })
+            .unwrap_or(1.0);
+        match name.as_str() {
+            "" => {}
+            "*" => wildcard = Some(q),
+            // x-gzip is an alias of gzip
+            "x-gzip" => accepted.push(("gzip".to_string(), q)),
+            _ => accepted.push((name, q)),
+        }
+    }
+
+    let mut preferred: Vec<(Encoding, f32)> = encodings
+        .into_iter()
+        .filter_map(|encoding| {
+            let q = accepted
+                .iter()
+                .find(|(name, _)| name == encoding.as_str())
+                .map(|(_, q)| *q)
+                .or(wildcard)
+                .unwrap_or(0.0);
+            (q > 0.0).then_some((encoding, q))
+        })
+        .collect();
+    // Stable sort keeps the given order for equal q-values
+    preferred.sort_by(|a, b| b.1.total_cmp(&a.1));
+    preferred
+        .into_iter()
+        .map(|(encoding, _)| encoding)
+        .collect()
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn encoder<R>(
+    reader: R,
+    encoding: Encoding,
+    level: Option<i32>,
+) -> Box<dyn AsyncRead + Unpin + Send>
+where
+    R: AsyncBufRead + Unpin + Send + 'static,
+{
+    let level = level.map(Level::Precise).unwrap_or(Level::Default);
+    match encoding {
+        Encoding::Gzip => Box::new(GzipEncoder::with_quality(reader, level)),
+        Encoding::Brotli => Box::new(BrotliEncoder::with_quality(reader, level)),
+        Encoding::Zstd => Box::new(ZstdEncoder::with_quality(reader, level)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compression::{is_compressible, negotiate, preferred_encodings};
+    use crate::config::{EncodeOptions, Encoding};
+    use bytes::BytesMut;
+    use http::Request;
+
+    fn negotiated(accept_encoding: &str) -> Option<Encoding> {
+        let options = EncodeOptions {
+            encodings: vec![
+                (Encoding::Zstd, None),
+                (Encoding::Brotli, Some(5)),
+                (Encoding::Gzip, None),
+            ],
+            min_length: 0,
+        };
+        let request = Request::builder()
+            .header("Accept-Encoding", accept_encoding)
+            .body(BytesMut::new())
+            .unwrap();
+        negotiate(&request, &options).map(|(encoding, _)| encoding)
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiated("gzip, deflate, br"), Some(Encoding::Brotli));
+        assert_eq!(negotiated("gzip;q=1.0, br;q=0.5"), Some(Encoding::Gzip));
+        assert_eq!(negotiated("x-gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiated("zstd;q=0, *"), Some(Encoding::Brotli));
+        assert_eq!(negotiated("*;q=0.1, gzip;Q=0.2"), Some(Encoding::Gzip));
+        assert_eq!(negotiated("identity"), None);
+        assert_eq!(negotiated("gzip;q=0"), None);
+        assert_eq!(negotiated(""), None);
+    }
+
+    #[test]
+    fn test_preferred_encodings() {
+        let request = Request::builder()
+            .header("Accept-Encoding", "gzip;q=0.8, zstd;q=0.8, br")
+            .body(BytesMut::new())
+            .unwrap();
+        assert_eq!(
+            preferred_encodings(&request, [Encoding::Gzip, Encoding::Zstd, Encoding::Brotli]),
+            vec![Encoding::Brotli, Encoding::Gzip, Encoding::Zstd]
+        );
+        let request = Request::builder().body(BytesMut::new()).unwrap();
+        assert!(preferred_encodings(&request, [Encoding::Gzip]).is_empty());
+    }
+
+    #[test]
+    fn test_compressible() {
+        assert!(is_compressible("text/html; charset=utf-8"));
+        assert!(is_compressible("application/javascript"));
+        assert!(is_compressible("image/svg+xml"));
+        assert!(!is_compressible("image/png"));
+        assert!(!is_compressible("video/mp4"));
+        assert!(!is_compressible("application/zip"));
+        assert!(!is_compressible("font/woff2"));
+    }
+}
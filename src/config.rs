@@ -3,10 +3,14 @@ use crate::server::Server;
 use crate::{build_servers, Args};
 use bollard::container::ListContainersOptions;
 use bollard::service::ListServicesOptions;
+use http::{HeaderName, HeaderValue, StatusCode};
 use kdl::{KdlDocument, KdlNode};
 use log::debug;
+use regex::Regex;
 use std::collections::HashMap;
+use std::net::IpAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::fs;
 #[cfg(feature = "trace")]
 use tracing::instrument;
@@ -18,11 +22,13 @@ pub enum Directive {
         path: String,
         fallback: Option<String>, // fallback file path
     },
-    FileServer,
+    FileServer {
+        options: FileServerOptions,
+    },
     ReverseProxy {
         pattern: String,
-        destinations: Vec<String>,
-        options: ReverseProxyOptions,
+        destinations: Vec<Destination>,
+        options: Box<ReverseProxyOptions>,
     },
     Redir {
         destination: String,
@@ -35,12 +41,77 @@ pub enum Directive {
         cert: String,
         key: String,
     },
+    Protocols {
+        protocols: Vec<String>,
+    },
+    Limits {
+        options: LimitsOptions,
+    },
+    Encode {
+        options: EncodeOptions,
+    },
+    HandleErrors {
+        options: HandleErrorsOptions,
+    },
+    Header {
+        pattern: String,
+        operations: Vec<HeaderOperation>,
+    },
+}
+
+/// Response header change of a `header` directive
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeaderOperation {
+    Set(HeaderName, HeaderValue), // "Field" "value", replaces every value
+    Add(HeaderName, HeaderValue), // "+Field" "value", appends a value
+    Remove(HeaderName),           // "-Field"
+}
+
+/// Backend of a reverse_proxy, `"http://big:8080" weight=3` gets three times the share
+#[derive(Debug, Clone, PartialEq)]
+pub struct Destination {
+    pub url: String,
+    pub weight: u32,
+}
+
+impl Destination {
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn new(url: impl Into<String>) -> Self {
+        Destination {
+            url: url.into(),
+            weight: 1,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum LoadBalancePolicy {
     RoundRobin,
     IPHash,
+    LeastConn,            // fewest requests and tunnels in flight
+    Cookie(StickyCookie), // sticky sessions, round robin for new clients
+    Random,
+    RandomChoose(usize), // least loaded of this many random backends
+}
+
+/// Cookie that keeps a client on the backend it was sent to first
+#[derive(Debug, Clone)]
+pub struct StickyCookie {
+    pub name: String,
+    pub path: String,
+    pub max_age: Option<u64>,   // seconds, a session cookie without it
+    pub secret: Option<String>, // HMAC key of the cookie value, random per start without it
+}
+
+impl Default for StickyCookie {
+    fn default() -> Self {
+        StickyCookie {
+            name: "cblt_lb".to_string(),
+            path: "/".to_string(),
+            max_age: None,
+            secret: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Default)]
@@ -49,6 +120,291 @@ pub struct ReverseProxyOptions {
     pub lb_interval: u64,
     pub lb_timeout: u64,
     pub lb_policy: Option<LoadBalancePolicy>,
+    pub header_up: Vec<HeaderOperation>, // request headers sent to the backend, with placeholders
+    pub header_down: Vec<HeaderOperation>, // response headers sent to the client, with placeholders
+    pub trusted_proxies: Vec<IpCidr>, // clients whose X-Forwarded-* and Forwarded values are kept
+    pub tls: UpstreamTlsOptions,
+    pub strip_prefix: Option<String>, // removed from the path sent to the backend
+    pub add_prefix: Option<String>,   // prepended after strip_prefix and rewrite
+    pub rewrite: Vec<PathRewrite>,    // applied in order after strip_prefix
+    pub keepalive: KeepAliveOptions,
+    pub health: Option<HealthCheckOptions>, // active health checks, enabled by health_uri
+    pub passive_health: Option<PassiveHealthOptions>, // enabled by any of the fail_* options
+    pub response_timeout: Option<Duration>, // for the response head of the backend
+}
+
+/// Background probes of the backends of a reverse_proxy
+#[derive(Debug, Clone)]
+pub struct HealthCheckOptions {
+    pub uri: String,
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub status: (u16, u16),  // expected status range
+    pub body: Option<Regex>, // must match the response body
+    pub passes: u32,         // consecutive passes that make a backend healthy again
+    pub fails: u32,          // consecutive failures that make it unhealthy
+}
+
+impl Default for HealthCheckOptions {
+    fn default() -> Self {
+        HealthCheckOptions {
+            uri: String::new(),
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(5),
+            status: (200, 299),
+            body: None,
+            passes: 1,
+            fails: 1,
+        }
+    }
+}
+
+/// Failures of proxied requests that take a backend out of rotation for a while
+#[derive(Debug, Clone)]
+pub struct PassiveHealthOptions {
+    pub window: Duration,        // failures older than this are forgotten
+    pub max_fails: u32,          // failures within the window that mark the backend dead
+    pub cooldown: Duration,      // how long it stays dead
+    pub status: Vec<(u16, u16)>, // backend responses that count as failures
+}
+
+impl Default for PassiveHealthOptions {
+    fn default() -> Self {
+        PassiveHealthOptions {
+            window: Duration::from_secs(30),
+            max_fails: 3,
+            cooldown: Duration::from_secs(30),
+            status: vec![(502, 504)],
+        }
+    }
+}
+
+/// Pool of idle backend connections of a reverse_proxy
+#[derive(Debug, Clone)]
+pub struct KeepAliveOptions {
+    pub max_idle: usize, // per backend, 0 disables the pool
+    pub idle_timeout: Duration,
+    pub max_lifetime: Option<Duration>,
+}
+
+impl Default for KeepAliveOptions {
+    fn default() -> Self {
+        KeepAliveOptions {
+            max_idle: 32,
+            idle_timeout: Duration::from_secs(60),
+            max_lifetime: None,
+        }
+    }
+}
+
+/// `rewrite "^/v1/(.*)$" "/api/$1"` of a reverse_proxy, the query string is kept
+#[derive(Debug, Clone)]
+pub struct PathRewrite {
+    pub regex: Regex,
+    pub replacement: String,
+}
+
+/// TLS to the https:// backends of a reverse_proxy
+#[derive(Debug, Clone, Default)]
+pub struct UpstreamTlsOptions {
+    pub ca: Option<String>,          // PEM bundle replacing the built-in roots
+    pub server_name: Option<String>, // SNI and verified name instead of the backend host
+    pub client_cert: Option<(String, String)>, // certificate and key for mTLS
+    pub insecure_skip_verify: bool,
+}
+
+/// Network in CIDR notation, a bare address is a single host network
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpCidr {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+impl IpCidr {
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn parse(cidr: &str) -> Result<IpCidr, CbltError> {
+        let invalid = || CbltError::KdlParseError {
+            details: format!("Invalid CIDR: {}", cidr),
+        };
+        let (addr, prefix) = match cidr.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (cidr, None),
+        };
+        let addr = addr.parse::<IpAddr>().map_err(|_| invalid())?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().map_err(|_| invalid())?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            return Err(invalid());
+        }
+        Ok(IpCidr { addr, prefix })
+    }
+
+    /// IPv4 addresses also match as IPv4-mapped IPv6, as seen on dual-stack sockets
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FileServerOptions {
+    pub index: Vec<String>, // index files of a directory, in order of preference
+    pub try_files: Vec<String>, // candidate paths with "{path}" placeholder
+    pub precompressed: Vec<Encoding>, // sidecar files to look for, in order of preference
+    pub browse: bool,       // list directories without an index file
+    pub strip_trailing_slash: bool, // redirect "/file.html/" to "/file.html"
+}
+
+impl Default for FileServerOptions {
+    fn default() -> Self {
+        FileServerOptions {
+            index: vec!["index.html".to_string()],
+            try_files: Vec::new(),
+            precompressed: Vec::new(),
+            browse: false,
+            strip_trailing_slash: false,
+        }
+    }
+}
+
+/// Status codes are inclusive ranges, e.g. (404, 404) or (500, 599) for "5xx"
+#[derive(Debug, Clone, Default)]
+pub struct HandleErrorsOptions {
+    pub statuses: Vec<(u16, u16)>, // handled statuses, every 4xx and 5xx when empty
+    pub pages: Vec<((u16, u16), String)>, // static page per status, relative to the root
+    pub directives: Vec<Directive>, // run when no page matches
+}
+
+impl HandleErrorsOptions {
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn handles(&self, status: StatusCode) -> bool {
+        let code = status.as_u16();
+        if self.statuses.is_empty() {
+            return (400..=599).contains(&code);
+        }
+        self.statuses
+            .iter()
+            .any(|(start, end)| (*start..=*end).contains(&code))
+    }
+
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn page(&self, status: StatusCode) -> Option<&str> {
+        let code = status.as_u16();
+        self.pages
+            .iter()
+            .find(|((start, end), _)| (*start..=*end).contains(&code))
+            .map(|(_, path)| path.as_str())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestLimits {
+    pub max_header_count: usize,
+    pub max_header_bytes: usize,
+    pub max_uri_length: usize,
+    pub max_body_size: usize,
+}
+
+/// Per host overrides of the global request limits
+#[derive(Debug, Clone, Default)]
+pub struct LimitsOptions {
+    pub max_header_count: Option<usize>,
+    pub max_header_bytes: Option<usize>,
+    pub max_uri_length: Option<usize>,
+    pub max_body_size: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Brotli,
+    Zstd,
+}
+
+impl Encoding {
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match name {
+            "gzip" => Some(Encoding::Gzip),
+            "br" => Some(Encoding::Brotli),
+            "zstd" => Some(Encoding::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Extension of a precompressed sidecar file
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gz",
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zst",
+        }
+    }
+
+    /// Content-Encoding token
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    fn levels(&self) -> std::ops::RangeInclusive<i32> {
+        match self {
+            Encoding::Gzip => 1..=9,
+            Encoding::Brotli => 0..=11,
+            Encoding::Zstd => 1..=22,
+        }
+    }
+}
+
+/// Response compression of a host, encodings are listed in order of preference
+#[derive(Debug, Clone)]
+pub struct EncodeOptions {
+    pub encodings: Vec<(Encoding, Option<i32>)>, // encoding, level
+    pub min_length: u64,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        EncodeOptions {
+            encodings: vec![(Encoding::Gzip, None)],
+            min_length: 1024,
+        }
+    }
+}
+
+impl RequestLimits {
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn merge(&self, options: &LimitsOptions) -> RequestLimits {
+        RequestLimits {
+            max_header_count: options.max_header_count.unwrap_or(self.max_header_count),
+            max_header_bytes: options.max_header_bytes.unwrap_or(self.max_header_bytes),
+            max_uri_length: options.max_uri_length.unwrap_or(self.max_uri_length),
+            max_body_size: options.max_body_size.unwrap_or(self.max_body_size),
+        }
+    }
 }
 
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
@@ -61,102 +417,7 @@ pub fn build_config(doc: &KdlDocument) -> Result<HashMap<String, Vec<Directive>>
 
         if let Some(children) = node.children() {
             for child_node in children.nodes() {
-                let child_name = child_node.name().value();
-
-                match child_name {
-                    "root" => {
-                        let args = get_string_args(child_node);
-                        if args.len() >= 2 {
-                            let pattern = args[0].to_string();
-                            let path = args[1].to_string();
-                            let fallback = if args.len() >= 3 {
-                                Some(args[2].to_string())
-                            } else {
-                                None
-                            };
-                            directives.push(Directive::Root {
-                                pattern,
-                                path,
-                                fallback,
-                            });
-                        } else {
-                            return Err(CbltError::KdlParseError {
-                                details: format!("Invalid 'root' directive for host {}", hostname),
-                            });
-                        }
-                    }
-                    "file_server" => {
-                        directives.push(Directive::FileServer);
-                    }
-                    "reverse_proxy" => {
-                        let args = get_string_args(child_node);
-                        if args.len() >= 2 {
-                            let pattern = args[0].to_string();
-                            let destinations = args[1..].iter().map(|s| s.to_string()).collect();
-
-                            let options = parse_reverse_proxy_options(child_node)?;
-                            directives.push(Directive::ReverseProxy {
-                                pattern,
-                                destinations,
-                                options,
-                            });
-                        } else {
-                            return Err(CbltError::KdlParseError {
-                                details: format!(
-                                    "Invalid 'reverse_proxy' directive for host {}",
-                                    hostname
-                                ),
-                            });
-                        }
-                    }
-                    "redir" => {
-                        let args = get_string_args(child_node);
-                        if !args.is_empty() {
-                            let destination = args[0].to_string();
-                            directives.push(Directive::Redir { destination });
-                        } else {
-                            return Err(CbltError::KdlParseError {
-                                details: format!("Invalid 'redir' directive for host {}", hostname),
-                            });
-                        }
-                    }
-                    "redirifnotcookie" => {
-                        let args = get_string_args(child_node);
-                        if !args.is_empty() {
-                            let cookiename = args[0].to_string();
-                            let destination = args[1].to_string();
-                            directives.push(Directive::RedirIfNotCookie {
-                                cookiename,
-                                destination,
-                            });
-                        } else {
-                            return Err(CbltError::KdlParseError {
-                                details: format!("Invalid 'redir' directive for host {}", hostname),
-                            });
-                        }
-                    }
-
-                    "tls" => {
-                        let args = get_string_args(child_node);
-                        if args.len() >= 2 {
-                            let cert = args[0].to_string();
-                            let key = args[1].to_string();
-                            directives.push(Directive::TlS { cert, key });
-                        } else {
-                            return Err(CbltError::KdlParseError {
-                                details: format!("Invalid 'tls' directive for host {}", hostname),
-                            });
-                        }
-                    }
-                    _ => {
-                        return Err(CbltError::KdlParseError {
-                            details: format!(
-                                "Unknown directive '{}' for host {}",
-                                child_name, hostname
-                            ),
-                        });
-                    }
-                }
+                directives.push(parse_directive(child_node, &hostname)?);
             }
         }
 
@@ -179,6 +440,236 @@ pub fn build_config(doc: &KdlDocument) -> Result<HashMap<String, Vec<Directive>>
     Ok(hosts)
 }
 
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn parse_directive(child_node: &KdlNode, hostname: &str) -> Result<Directive, CbltError> {
+    let child_name = child_node.name().value();
+    match child_name {
+        "root" => {
+            let args = get_string_args(child_node);
+            if args.len() >= 2 {
+                let pattern = args[0].to_string();
+                let path = args[1].to_string();
+                let fallback = if args.len() >= 3 {
+                    Some(args[2].to_string())
+                } else {
+                    None
+                };
+                Ok(Directive::Root {
+                    pattern,
+                    path,
+                    fallback,
+                })
+            } else {
+                Err(CbltError::KdlParseError {
+                    details: format!("Invalid 'root' directive for host {}", hostname),
+                })
+            }
+        }
+        "file_server" => {
+            let options = parse_file_server_options(child_node)?;
+            Ok(Directive::FileServer { options })
+        }
+        "reverse_proxy" => {
+            let args = get_string_args(child_node);
+            if args.len() >= 2 {
+                let pattern = args[0].to_string();
+                let destinations = parse_destinations(child_node)?;
+
+                let options = parse_reverse_proxy_options(child_node)?;
+                Ok(Directive::ReverseProxy {
+                    pattern,
+                    destinations,
+                    options: Box::new(options),
+                })
+            } else {
+                Err(CbltError::KdlParseError {
+                    details: format!("Invalid 'reverse_proxy' directive for host {}", hostname),
+                })
+            }
+        }
+        "redir" => {
+            let args = get_string_args(child_node);
+            if !args.is_empty() {
+                let destination = args[0].to_string();
+                Ok(Directive::Redir { destination })
+            } else {
+                Err(CbltError::KdlParseError {
+                    details: format!("Invalid 'redir' directive for host {}", hostname),
+                })
+            }
+        }
+        "redirifnotcookie" => {
+            let args = get_string_args(child_node);
+            if !args.is_empty() {
+                let cookiename = args[0].to_string();
+                let destination = args[1].to_string();
+                Ok(Directive::RedirIfNotCookie {
+                    cookiename,
+                    destination,
+                })
+            } else {
+                Err(CbltError::KdlParseError {
+                    details: format!("Invalid 'redir' directive for host {}", hostname),
+                })
+            }
+        }
+
+        "tls" => {
+            let args = get_string_args(child_node);
+            if args.len() >= 2 {
+                let cert = args[0].to_string();
+                let key = args[1].to_string();
+                Ok(Directive::TlS { cert, key })
+            } else {
+                Err(CbltError::KdlParseError {
+                    details: format!("Invalid 'tls' directive for host {}", hostname),
+                })
+            }
+        }
+        "protocols" => {
+            let args = get_string_args(child_node);
+            if !args.is_empty() && args.iter().all(|p| *p == "h1" || *p == "h2") {
+                let protocols = args.iter().map(|p| p.to_string()).collect();
+                Ok(Directive::Protocols { protocols })
+            } else {
+                Err(CbltError::KdlParseError {
+                    details: format!("Invalid 'protocols' directive for host {}", hostname),
+                })
+            }
+        }
+        "limits" => {
+            let options = parse_limits_options(child_node)?;
+            Ok(Directive::Limits { options })
+        }
+        "encode" => {
+            let options = parse_encode_options(child_node)?;
+            Ok(Directive::Encode { options })
+        }
+        "handle_errors" => {
+            let options = parse_handle_errors_options(child_node, hostname)?;
+            Ok(Directive::HandleErrors { options })
+        }
+        "header" => {
+            let args = get_string_args(child_node);
+            let Some((pattern, operation)) = args.split_first() else {
+                return Err(CbltError::KdlParseError {
+                    details: format!("Invalid 'header' directive for host {}", hostname),
+                });
+            };
+            // A single operation can follow the pattern, more go into the children
+            let mut operations = Vec::new();
+            if let Some((field, value)) = operation.split_first() {
+                operations.push(parse_header_operation(field, value.first().copied())?);
+            }
+            if let Some(children) = child_node.children() {
+                for child in children.nodes() {
+                    let value = get_string_args(child).first().copied();
+                    operations.push(parse_header_operation(child.name().value(), value)?);
+                }
+            }
+            if operations.is_empty() {
+                return Err(CbltError::KdlParseError {
+                    details: format!("No header operations for host {}", hostname),
+                });
+            }
+            Ok(Directive::Header {
+                pattern: pattern.to_string(),
+                operations,
+            })
+        }
+        _ => Err(CbltError::KdlParseError {
+            details: format!("Unknown directive '{}' for host {}", child_name, hostname),
+        }),
+    }
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn parse_handle_errors_options(
+    node: &KdlNode,
+    hostname: &str,
+) -> Result<HandleErrorsOptions, CbltError> {
+    let mut options = HandleErrorsOptions {
+        statuses: get_string_args(node)
+            .into_iter()
+            .map(parse_status_range)
+            .collect::<Result<_, _>>()?,
+        ..HandleErrorsOptions::default()
+    };
+
+    if let Some(children) = node.children() {
+        for child in children.nodes() {
+            match child.name().value() {
+                "page" => match get_string_args(child)[..] {
+                    [status, path] => options
+                        .pages
+                        .push((parse_status_range(status)?, path.to_string())),
+                    _ => {
+                        return Err(CbltError::KdlParseError {
+                            details: format!(
+                                "Invalid 'page' in handle_errors for host {}",
+                                hostname
+                            ),
+                        });
+                    }
+                },
+                "handle_errors" => {
+                    return Err(CbltError::KdlParseError {
+                        details: format!("Nested 'handle_errors' for host {}", hostname),
+                    });
+                }
+                _ => options.directives.push(parse_directive(child, hostname)?),
+            }
+        }
+    }
+
+    Ok(options)
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn parse_header_operation(field: &str, value: Option<&str>) -> Result<HeaderOperation, CbltError> {
+    let invalid = || CbltError::KdlParseError {
+        details: format!("Invalid header operation: {} {:?}", field, value),
+    };
+    let header_name = |name: &str| HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid());
+    let header_value = || {
+        value
+            .and_then(|value| HeaderValue::from_str(value).ok())
+            .ok_or_else(invalid)
+    };
+    match (field.as_bytes().first(), value) {
+        (Some(b'-'), None) => Ok(HeaderOperation::Remove(header_name(&field[1..])?)),
+        (Some(b'-'), Some(_)) => Err(invalid()),
+        (Some(b'+'), _) => Ok(HeaderOperation::Add(
+            header_name(&field[1..])?,
+            header_value()?,
+        )),
+        _ => Ok(HeaderOperation::Set(header_name(field)?, header_value()?)),
+    }
+}
+
+/// Parses "404" or a class like "5xx" into an inclusive range
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn parse_status_range(status: &str) -> Result<(u16, u16), CbltError> {
+    let invalid = || CbltError::KdlParseError {
+        details: format!("Invalid status code: {}", status),
+    };
+    let range = match status.as_bytes() {
+        [class @ b'1'..=b'5', b'x', b'x'] => {
+            let start = u16::from(class - b'0') * 100;
+            (start, start + 99)
+        }
+        _ => {
+            let code = status.parse::<u16>().map_err(|_| invalid())?;
+            (code, code)
+        }
+    };
+    if (100..=599).contains(&range.0) && (100..=599).contains(&range.1) {
+        Ok(range)
+    } else {
+        Err(invalid())
+    }
+}
+
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
 fn get_string_args<'a>(node: &'a KdlNode) -> Vec<&'a str> {
     node.entries()
@@ -194,6 +685,17 @@ fn parse_reverse_proxy_options(node: &KdlNode) -> Result<ReverseProxyOptions, Cb
         lb_interval: 60,
         lb_timeout: 1,
         lb_policy: Some(LoadBalancePolicy::RoundRobin),
+        header_up: Vec::new(),
+        header_down: Vec::new(),
+        trusted_proxies: Vec::new(),
+        tls: UpstreamTlsOptions::default(),
+        strip_prefix: None,
+        add_prefix: None,
+        rewrite: Vec::new(),
+        keepalive: KeepAliveOptions::default(),
+        health: None,
+        passive_health: None,
+        response_timeout: None,
     };
 
     if let Some(children) = node.children() {
@@ -234,6 +736,21 @@ fn parse_reverse_proxy_options(node: &KdlNode) -> Result<ReverseProxyOptions, Cb
                             "ip_hash" => {
                                 options.lb_policy = Some(LoadBalancePolicy::IPHash);
                             }
+                            "least_conn" => {
+                                options.lb_policy = Some(LoadBalancePolicy::LeastConn);
+                            }
+                            "cookie" => {
+                                options.lb_policy =
+                                    Some(LoadBalancePolicy::Cookie(parse_sticky_cookie(child)?));
+                            }
+                            "random" => {
+                                options.lb_policy = Some(LoadBalancePolicy::Random);
+                            }
+                            "random_choose" => {
+                                options.lb_policy = Some(LoadBalancePolicy::RandomChoose(
+                                    parse_random_choose(args.get(1).copied())?,
+                                ));
+                            }
                             _ => {
                                 return Err(CbltError::KdlParseError {
                                     details: format!("Unknown lb_policy '{}'", policy_name),
@@ -242,18 +759,488 @@ fn parse_reverse_proxy_options(node: &KdlNode) -> Result<ReverseProxyOptions, Cb
                         }
                     }
                 }
-                _ => {
-                    return Err(CbltError::KdlParseError {
-                        details: format!("Unknown reverse_proxy option '{}'", name),
-                    });
-                }
+                "tls_ca" | "tls_server_name" => {
+                    let args = get_string_args(child);
+                    let Some(value) = args.first() else {
+                        return Err(CbltError::KdlParseError {
+                            details: format!("Missing value of '{}'", name),
+                        });
+                    };
+                    if name == "tls_ca" {
+                        options.tls.ca = Some(value.to_string());
+                    } else {
+                        options.tls.server_name = Some(value.to_string());
+                    }
+                }
+                "tls_client_cert" => match get_string_args(child)[..] {
+                    [cert, key] => {
+                        options.tls.client_cert = Some((cert.to_string(), key.to_string()));
+                    }
+                    _ => {
+                        return Err(CbltError::KdlParseError {
+                            details: "'tls_client_cert' needs a certificate and a key".to_string(),
+                        });
+                    }
+                },
+                "strip_prefix" | "add_prefix" => {
+                    let args = get_string_args(child);
+                    let Some(prefix) = args.first().filter(|prefix| prefix.starts_with('/')) else {
+                        return Err(CbltError::KdlParseError {
+                            details: format!("'{}' needs a prefix starting with '/'", name),
+                        });
+                    };
+                    let prefix = prefix.trim_end_matches('/').to_string();
+                    if name == "strip_prefix" {
+                        options.strip_prefix = Some(prefix);
+                    } else {
+                        options.add_prefix = Some(prefix);
+                    }
+                }
+                "rewrite" => match get_string_args(child)[..] {
+                    [regex, replacement] => {
+                        options.rewrite.push(PathRewrite {
+                            regex: Regex::new(regex)?,
+                            replacement: replacement.to_string(),
+                        });
+                    }
+                    _ => {
+                        return Err(CbltError::KdlParseError {
+                            details: "'rewrite' needs a regex and a replacement".to_string(),
+                        });
+                    }
+                },
+                "keepalive_max_idle" | "keepalive_idle_timeout" | "keepalive_max_lifetime" => {
+                    let args = get_string_args(child);
+                    let Some(value) = args.first() else {
+                        return Err(CbltError::KdlParseError {
+                            details: format!("Missing value of '{}'", name),
+                        });
+                    };
+                    match name {
+                        "keepalive_max_idle" => options.keepalive.max_idle = value.parse()?,
+                        "keepalive_idle_timeout" => {
+                            options.keepalive.idle_timeout =
+                                value.parse::<humantime::Duration>()?.into();
+                        }
+                        _ => {
+                            options.keepalive.max_lifetime =
+                                Some(value.parse::<humantime::Duration>()?.into());
+                        }
+                    }
+                }
+                "health_uri" | "health_interval" | "health_timeout" | "health_status"
+                | "health_body" | "health_passes" | "health_fails" => {
+                    let args = get_string_args(child);
+                    let Some(value) = args.first() else {
+                        return Err(CbltError::KdlParseError {
+                            details: format!("Missing value of '{}'", name),
+                        });
+                    };
+                    let health = options
+                        .health
+                        .get_or_insert_with(HealthCheckOptions::default);
+                    match name {
+                        "health_uri" => {
+                            if !value.starts_with('/') {
+                                return Err(CbltError::KdlParseError {
+                                    details: format!("'health_uri' must start with '/': {}", value),
+                                });
+                            }
+                            health.uri = value.to_string();
+                        }
+                        "health_interval" => {
+                            health.interval = value.parse::<humantime::Duration>()?.into();
+                        }
+                        "health_timeout" => {
+                            health.timeout = value.parse::<humantime::Duration>()?.into();
+                        }
+                        "health_status" => health.status = parse_status_range(value)?,
+                        "health_body" => health.body = Some(Regex::new(value)?),
+                        "health_passes" => health.passes = value.parse::<u32>()?.max(1),
+                        _ => health.fails = value.parse::<u32>()?.max(1),
+                    }
+                }
+                "fail_window" | "fail_max" | "fail_cooldown" | "fail_status" => {
+                    let args = get_string_args(child);
+                    if args.is_empty() {
+                        return Err(CbltError::KdlParseError {
+                            details: format!("Missing value of '{}'", name),
+                        });
+                    }
+                    let passive = options
+                        .passive_health
+                        .get_or_insert_with(PassiveHealthOptions::default);
+                    match name {
+                        "fail_window" => {
+                            passive.window = args[0].parse::<humantime::Duration>()?.into();
+                        }
+                        "fail_max" => passive.max_fails = args[0].parse::<u32>()?.max(1),
+                        "fail_cooldown" => {
+                            passive.cooldown = args[0].parse::<humantime::Duration>()?.into();
+                        }
+                        _ => {
+                            passive.status = args
+                                .into_iter()
+                                .map(parse_status_range)
+                                .collect::<Result<_, _>>()?;
+                        }
+                    }
+                }
+                "response_timeout" => {
+                    let Some(value) = get_string_args(child).first().copied() else {
+                        return Err(CbltError::KdlParseError {
+                            details: "Missing value of 'response_timeout'".to_string(),
+                        });
+                    };
+                    options.response_timeout = Some(value.parse::<humantime::Duration>()?.into());
+                }
+                "tls_insecure_skip_verify" => {
+                    options.tls.insecure_skip_verify = true;
+                }
+                "trusted_proxies" => {
+                    for cidr in get_string_args(child) {
+                        options.trusted_proxies.push(IpCidr::parse(cidr)?);
+                    }
+                }
+                "header_up" | "header_down" => {
+                    let args = get_string_args(child);
+                    let Some((field, value)) = args.split_first() else {
+                        return Err(CbltError::KdlParseError {
+                            details: format!("Missing header in '{}'", name),
+                        });
+                    };
+                    let operation = parse_header_operation(field, value.first().copied())?;
+                    if name == "header_up" {
+                        options.header_up.push(operation);
+                    } else {
+                        options.header_down.push(operation);
+                    }
+                }
+                _ => {
+                    return Err(CbltError::KdlParseError {
+                        details: format!("Unknown reverse_proxy option '{}'", name),
+                    });
+                }
+            }
+        }
+    }
+    if options
+        .health
+        .as_ref()
+        .is_some_and(|health| health.uri.is_empty())
+    {
+        return Err(CbltError::KdlParseError {
+            details: "Health check options need 'health_uri'".to_string(),
+        });
+    }
+    if options
+        .health
+        .as_ref()
+        .is_some_and(|health| health.interval.is_zero())
+    {
+        return Err(CbltError::KdlParseError {
+            details: "'health_interval' must not be zero".to_string(),
+        });
+    }
+    if options
+        .passive_health
+        .as_ref()
+        .is_some_and(|passive| passive.window.is_zero())
+    {
+        return Err(CbltError::KdlParseError {
+            details: "'fail_window' must not be zero".to_string(),
+        });
+    }
+
+    Ok(options)
+}
+
+/// Destinations of a reverse_proxy, the arguments after the pattern. A `weight`
+/// property applies to the destination before it.
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn parse_destinations(node: &KdlNode) -> Result<Vec<Destination>, CbltError> {
+    let mut destinations: Vec<Destination> = Vec::new();
+    let mut pattern_seen = false;
+    for entry in node.entries() {
+        match entry.name().map(|name| name.value()) {
+            None => {
+                let Some(url) = entry.value().as_string() else {
+                    continue;
+                };
+                if pattern_seen {
+                    destinations.push(Destination::new(url));
+                } else {
+                    pattern_seen = true;
+                }
+            }
+            Some("weight") => {
+                let weight = match (entry.value().as_i64(), entry.value().as_string()) {
+                    (Some(weight), _) => u32::try_from(weight).ok(),
+                    (_, Some(weight)) => weight.parse::<u32>().ok(),
+                    _ => None,
+                };
+                let Some(destination) = destinations.last_mut() else {
+                    return Err(CbltError::KdlParseError {
+                        details: "'weight' must follow a reverse_proxy destination".to_string(),
+                    });
+                };
+                match weight {
+                    Some(weight) if weight > 0 => destination.weight = weight,
+                    _ => {
+                        return Err(CbltError::KdlParseError {
+                            details: format!("Invalid weight of {}: {}", destination.url, entry),
+                        });
+                    }
+                }
+            }
+            Some(name) => {
+                return Err(CbltError::KdlParseError {
+                    details: format!("Unknown reverse_proxy property '{}'", name),
+                });
+            }
+        }
+    }
+    Ok(destinations)
+}
+
+/// Number of choices of `lb_policy "random_choose" "2"`, two without it
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn parse_random_choose(choices: Option<&str>) -> Result<usize, CbltError> {
+    match choices {
+        None => Ok(2),
+        Some(choices) => match choices.parse::<usize>() {
+            Ok(choices) if choices >= 1 => Ok(choices),
+            _ => Err(CbltError::KdlParseError {
+                details: format!("Invalid number of random_choose choices: {}", choices),
+            }),
+        },
+    }
+}
+
+/// Children of `lb_policy "cookie" { ... }`
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn parse_sticky_cookie(node: &KdlNode) -> Result<StickyCookie, CbltError> {
+    let mut cookie = StickyCookie::default();
+    if let Some(children) = node.children() {
+        for child in children.nodes() {
+            let name = child.name().value();
+            let args = get_string_args(child);
+            let value = args.first().ok_or_else(|| CbltError::KdlParseError {
+                details: format!("Missing value of '{}'", name),
+            })?;
+            match name {
+                "lb_cookie_name" => {
+                    if value.is_empty() || HeaderName::from_bytes(value.as_bytes()).is_err() {
+                        return Err(CbltError::KdlParseError {
+                            details: format!("Invalid cookie name: {}", value),
+                        });
+                    }
+                    cookie.name = value.to_string();
+                }
+                "lb_cookie_path" => {
+                    if !value.starts_with('/') || value.contains(';') {
+                        return Err(CbltError::KdlParseError {
+                            details: format!("Invalid cookie path: {}", value),
+                        });
+                    }
+                    cookie.path = value.to_string();
+                }
+                "lb_cookie_max_age" => {
+                    // Seconds, or a duration like "1h"
+                    let max_age = match value.parse::<u64>() {
+                        Ok(seconds) => seconds,
+                        Err(_) => value.parse::<humantime::Duration>()?.as_secs(),
+                    };
+                    cookie.max_age = Some(max_age);
+                }
+                "lb_cookie_secret" => cookie.secret = Some(value.to_string()),
+                _ => {
+                    return Err(CbltError::KdlParseError {
+                        details: format!("Unknown lb_policy \"cookie\" option '{}'", name),
+                    });
+                }
+            }
+        }
+    }
+    Ok(cookie)
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn parse_limits_options(node: &KdlNode) -> Result<LimitsOptions, CbltError> {
+    let mut options = LimitsOptions::default();
+
+    if let Some(children) = node.children() {
+        for child in children.nodes() {
+            let name = child.name().value();
+            let args = get_string_args(child);
+            let value = args.first().ok_or_else(|| CbltError::KdlParseError {
+                details: format!("Missing value for limits option '{}'", name),
+            })?;
+            match name {
+                "max_header_count" => {
+                    options.max_header_count = Some(value.parse()?);
+                }
+                "max_header_bytes" => {
+                    options.max_header_bytes = Some(parse_size(value)?);
+                }
+                "max_uri_length" => {
+                    options.max_uri_length = Some(parse_size(value)?);
+                }
+                "max_body_size" => {
+                    options.max_body_size = Some(parse_size(value)?);
+                }
+                _ => {
+                    return Err(CbltError::KdlParseError {
+                        details: format!("Unknown limits option '{}'", name),
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(options)
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn parse_file_server_options(node: &KdlNode) -> Result<FileServerOptions, CbltError> {
+    let mut options = FileServerOptions::default();
+
+    if let Some(children) = node.children() {
+        for child in children.nodes() {
+            let name = child.name().value();
+            let args = get_string_args(child);
+            match name {
+                "browse" => {
+                    options.browse = true;
+                }
+                "strip_trailing_slash" => {
+                    options.strip_trailing_slash = true;
+                }
+                "index" | "try_files" if args.is_empty() => {
+                    return Err(CbltError::KdlParseError {
+                        details: format!("Missing values for file_server option '{}'", name),
+                    });
+                }
+                "index" => {
+                    options.index = args.iter().map(|s| s.to_string()).collect();
+                }
+                "try_files" => {
+                    options.try_files = args.iter().map(|s| s.to_string()).collect();
+                }
+                "precompressed" => {
+                    options.precompressed = if args.is_empty() {
+                        vec![Encoding::Brotli, Encoding::Zstd, Encoding::Gzip]
+                    } else {
+                        args.iter()
+                            .map(|arg| {
+                                Encoding::from_name(arg).ok_or_else(|| CbltError::KdlParseError {
+                                    details: format!("Unknown encoding '{}'", arg),
+                                })
+                            })
+                            .collect::<Result<_, _>>()?
+                    };
+                }
+                _ => {
+                    return Err(CbltError::KdlParseError {
+                        details: format!("Unknown file_server option '{}'", name),
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(options)
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn parse_encode_options(node: &KdlNode) -> Result<EncodeOptions, CbltError> {
+    let mut options = EncodeOptions {
+        encodings: Vec::new(),
+        ..EncodeOptions::default()
+    };
+
+    let args = get_string_args(node);
+    if args == ["off"] {
+        return Ok(options);
+    }
+    for name in args {
+        let encoding = Encoding::from_name(name).ok_or_else(|| CbltError::KdlParseError {
+            details: format!("Unknown encoding '{}'", name),
+        })?;
+        options.encodings.push((encoding, None));
+    }
+
+    if let Some(children) = node.children() {
+        for child in children.nodes() {
+            let name = child.name().value();
+            let args = get_string_args(child);
+            if name == "minimum_length" {
+                let value = args.first().ok_or_else(|| CbltError::KdlParseError {
+                    details: "Missing value for encode option 'minimum_length'".to_string(),
+                })?;
+                options.min_length = parse_size(value)? as u64;
+                continue;
+            }
+
+            let encoding = Encoding::from_name(name).ok_or_else(|| CbltError::KdlParseError {
+                details: format!("Unknown encode option '{}'", name),
+            })?;
+            let level = match args.first() {
+                Some(level) => {
+                    let level = level.parse::<i32>()?;
+                    if !encoding.levels().contains(&level) {
+                        return Err(CbltError::KdlParseError {
+                            details: format!("Invalid {} level '{}'", name, level),
+                        });
+                    }
+                    Some(level)
+                }
+                None => None,
+            };
+            match options.encodings.iter_mut().find(|(e, _)| *e == encoding) {
+                Some(entry) => entry.1 = level,
+                None => options.encodings.push((encoding, level)),
             }
         }
     }
 
+    if options.encodings.is_empty() {
+        return Err(CbltError::KdlParseError {
+            details: "No encodings in 'encode' directive".to_string(),
+        });
+    }
+
     Ok(options)
 }
 
+/// Parses sizes like "512", "16KB" or "10MB" into bytes
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn parse_size(size: &str) -> Result<usize, CbltError> {
+    let size = size.trim();
+    let digits_end = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(digits_end);
+    let multiplier = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1024,
+        "M" | "MB" => 1024 * 1024,
+        "G" | "GB" => 1024 * 1024 * 1024,
+        _ => {
+            return Err(CbltError::InvalidSize {
+                details: size.to_string(),
+            });
+        }
+    };
+    number
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| CbltError::InvalidSize {
+            details: size.to_string(),
+        })
+}
+
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
 pub async fn load_servers_from_config(args: Arc<Args>) -> Result<HashMap<u16, Server>, CbltError> {
     let cbltfile_content = fs::read_to_string(&args.cfg).await?;
@@ -286,7 +1273,7 @@ pub async fn load_servers_from_docker(_args: Arc<Args>) -> Result<HashMap<u16, S
                 if labels.keys().any(|k| k.starts_with("cblt.")) {
                     // Get service name
                     let service_name = spec.name.ok_or(CbltError::ServiceNameNotFound)?;
-                    let mut destinations: Vec<String> = Vec::new();
+                    let mut destinations: Vec<Destination> = Vec::new();
                     let containers = docker
                         .list_containers(Some(ListContainersOptions::<String> {
                             all: false,
@@ -304,7 +1291,28 @@ pub async fn load_servers_from_docker(_args: Arc<Args>) -> Result<HashMap<u16, S
                                 Some(name_all) => {
                                     let container_name = name_all.replace("/", "");
                                     debug!("{container_name}");
-                                    destinations.push(container_name);
+                                    // cblt.weight of the container, otherwise of the service
+                                    let weight_label = container
+                                        .labels
+                                        .as_ref()
+                                        .and_then(|container_labels| {
+                                            container_labels.get("cblt.weight")
+                                        })
+                                        .or_else(|| labels.get("cblt.weight"));
+                                    let weight = match weight_label {
+                                        Some(weight) => weight
+                                            .parse::<u32>()
+                                            .ok()
+                                            .filter(|weight| *weight > 0)
+                                            .ok_or_else(|| CbltError::InvalidLabelFormat {
+                                                details: "cblt.weight".to_string(),
+                                            })?,
+                                        None => 1,
+                                    };
+                                    destinations.push(Destination {
+                                        url: container_name,
+                                        weight,
+                                    });
                                 }
                             }
                         } else {
@@ -375,6 +1383,15 @@ pub async fn load_servers_from_docker(_args: Arc<Args>) -> Result<HashMap<u16, S
                         match policy_str.as_str() {
                             "round_robin" => Some(LoadBalancePolicy::RoundRobin),
                             "ip_hash" => Some(LoadBalancePolicy::IPHash),
+                            "least_conn" => Some(LoadBalancePolicy::LeastConn),
+                            "cookie" => Some(LoadBalancePolicy::Cookie(StickyCookie::default())),
+                            "random" => Some(LoadBalancePolicy::Random),
+                            // "random_choose 3"
+                            policy if policy.starts_with("random_choose") => {
+                                Some(LoadBalancePolicy::RandomChoose(parse_random_choose(
+                                    policy.split_whitespace().nth(1),
+                                )?))
+                            }
                             _ => {
                                 return Err(CbltError::KdlParseError {
                                     details: format!("Unknown lb_policy '{}'", policy_str),
@@ -420,17 +1437,21 @@ pub async fn load_servers_from_docker(_args: Arc<Args>) -> Result<HashMap<u16, S
                         lb_interval,
                         lb_timeout,
                         lb_policy,
+                        ..ReverseProxyOptions::default()
                     };
 
                     // Build the ReverseProxy directive
                     let destinations = destinations
-                        .iter()
-                        .map(|s| format!("{}:{}", s, port))
+                        .into_iter()
+                        .map(|destination| Destination {
+                            url: format!("{}:{}", destination.url, port),
+                            weight: destination.weight,
+                        })
                         .collect();
                     let reverse_proxy_directive = Directive::ReverseProxy {
                         pattern: path.clone(),
                         destinations,
-                        options,
+                        options: Box::new(options),
                     };
 
                     // For each host, add the directives
@@ -460,9 +1481,14 @@ pub async fn load_servers_from_docker(_args: Arc<Args>) -> Result<HashMap<u16, S
 
 #[cfg(test)]
 mod tests {
-    use crate::config::build_config;
+    use crate::config::{
+        build_config, parse_size, Destination, Directive, Encoding, HeaderOperation, IpCidr,
+        LoadBalancePolicy,
+    };
+    use http::{HeaderName, HeaderValue, StatusCode};
     use kdl::KdlDocument;
     use std::error::Error;
+    use std::time::Duration;
 
     #[test]
     fn test_simple() -> Result<(), Box<dyn Error>> {
@@ -515,6 +1541,251 @@ example.com {
         Ok(())
     }
 
+    #[test]
+    fn test_protocols() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"*:443" {
+    root "*" "/path/to/folder"
+    file_server
+    tls "/path/to/your/certificate.crt" "/path/to/your/private.key"
+    protocols "h1"
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        println!("{:#?}", config);
+
+        let cblt_file = r#"
+"*:443" {
+    protocols "h3"
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        assert!(build_config(&doc).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_limits() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"*:80" {
+    limits {
+        max_header_count "50"
+        max_header_bytes "8KB"
+        max_uri_length "2048"
+        max_body_size "1MB"
+    }
+    root "*" "/path/to/folder"
+    file_server
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        println!("{:#?}", config);
+
+        assert_eq!(parse_size("512")?, 512);
+        assert_eq!(parse_size("16KB")?, 16 * 1024);
+        assert_eq!(parse_size("10mb")?, 10 * 1024 * 1024);
+        assert!(parse_size("10XB").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"*:80" {
+    encode "zstd" "br" {
+        gzip "6"
+        br "4"
+        minimum_length "512"
+    }
+    root "*" "/path/to/folder"
+    file_server
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        println!("{:#?}", config);
+        match config["*:80"].first() {
+            Some(Directive::Encode { options }) => {
+                assert_eq!(
+                    options.encodings,
+                    vec![
+                        (Encoding::Zstd, None),
+                        (Encoding::Brotli, Some(4)),
+                        (Encoding::Gzip, Some(6))
+                    ]
+                );
+                assert_eq!(options.min_length, 512);
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+
+        let doc: KdlDocument = r#""*:80" { encode "off"; file_server; }"#.parse()?;
+        assert!(build_config(&doc).is_ok());
+        let doc: KdlDocument = r#""*:80" { encode "deflate"; file_server; }"#.parse()?;
+        assert!(build_config(&doc).is_err());
+        let doc: KdlDocument = r#""*:80" { encode { zstd "30"; }; file_server; }"#.parse()?;
+        assert!(build_config(&doc).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_handle_errors() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"*:80" {
+    root "*" "/path/to/folder"
+    file_server
+    handle_errors "404" "5xx" {
+        root "*" "/path/to/errors"
+        page "404" "/404.html"
+        reverse_proxy "*" "http://errors:8080"
+    }
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        println!("{:#?}", config);
+        match config["*:80"].get(2) {
+            Some(Directive::HandleErrors { options }) => {
+                assert_eq!(options.statuses, vec![(404, 404), (500, 599)]);
+                assert!(options.handles(StatusCode::NOT_FOUND));
+                assert!(options.handles(StatusCode::BAD_GATEWAY));
+                assert!(!options.handles(StatusCode::FORBIDDEN));
+                assert_eq!(options.page(StatusCode::NOT_FOUND), Some("/404.html"));
+                assert_eq!(options.page(StatusCode::BAD_GATEWAY), None);
+                assert_eq!(options.directives.len(), 2);
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+
+        let doc: KdlDocument =
+            r#""*:80" { handle_errors { page "4xx" "/4xx.html"; }; }"#.parse()?;
+        match build_config(&doc)?["*:80"].first() {
+            Some(Directive::HandleErrors { options }) => {
+                assert!(options.handles(StatusCode::FORBIDDEN));
+                assert!(!options.handles(StatusCode::OK));
+                assert_eq!(options.page(StatusCode::FORBIDDEN), Some("/4xx.html"));
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+
+        for invalid in [
+            r#""*:80" { handle_errors "6xx" { page "6xx" "/e.html"; }; }"#,
+            r#""*:80" { handle_errors { page "404"; }; }"#,
+            r#""*:80" { handle_errors { handle_errors; }; }"#,
+        ] {
+            let doc: KdlDocument = invalid.parse()?;
+            assert!(build_config(&doc).is_err());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_header() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"*:80" {
+    header "*" {
+        Strict-Transport-Security "max-age=31536000"
+        +Link "</style.css>; rel=preload"
+        -Server
+    }
+    header "/assets/*" "Cache-Control" "max-age=3600"
+    file_server
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        println!("{:#?}", config);
+        match &config["*:80"][..2] {
+            [Directive::Header {
+                pattern,
+                operations,
+            }, Directive::Header {
+                pattern: assets_pattern,
+                operations: assets_operations,
+            }] => {
+                assert_eq!(pattern, "*");
+                assert_eq!(
+                    operations,
+                    &vec![
+                        HeaderOperation::Set(
+                            HeaderName::from_static("strict-transport-security"),
+                            HeaderValue::from_static("max-age=31536000")
+                        ),
+                        HeaderOperation::Add(
+                            HeaderName::from_static("link"),
+                            HeaderValue::from_static("</style.css>; rel=preload")
+                        ),
+                        HeaderOperation::Remove(HeaderName::from_static("server")),
+                    ]
+                );
+                assert_eq!(assets_pattern, "/assets/*");
+                assert_eq!(
+                    assets_operations,
+                    &vec![HeaderOperation::Set(
+                        HeaderName::from_static("cache-control"),
+                        HeaderValue::from_static("max-age=3600")
+                    )]
+                );
+            }
+            other => panic!("Unexpected directives {:?}", other),
+        }
+
+        for invalid in [
+            r#""*:80" { header "*"; }"#,
+            r#""*:80" { header "*" "X-Empty"; }"#,
+            r#""*:80" { header "*" "-Server" "value"; }"#,
+            r#""*:80" { header "*" "Bad Name" "value"; }"#,
+        ] {
+            let doc: KdlDocument = invalid.parse()?;
+            assert!(build_config(&doc).is_err());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_server_options() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"*:80" {
+    root "*" "/path/to/folder"
+    file_server {
+        index "index.html" "index.htm"
+        try_files "{path}" "{path}.html" "/404.html"
+        precompressed "zstd" "gzip"
+        browse
+        strip_trailing_slash
+    }
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        println!("{:#?}", config);
+        match config["*:80"].get(1) {
+            Some(Directive::FileServer { options }) => {
+                assert_eq!(options.precompressed, vec![Encoding::Zstd, Encoding::Gzip]);
+                assert!(options.browse);
+                assert!(options.strip_trailing_slash);
+                assert_eq!(options.index, vec!["index.html", "index.htm"]);
+                assert_eq!(
+                    options.try_files,
+                    vec!["{path}", "{path}.html", "/404.html"]
+                );
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+
+        let doc: KdlDocument = r#""*:80" { file_server { precompressed "lz4"; }; }"#.parse()?;
+        assert!(build_config(&doc).is_err());
+
+        Ok(())
+    }
+
     #[test]
     fn test_reverse_proxy_with_options() -> Result<(), Box<dyn Error>> {
         let cblt_file = r#"
@@ -530,6 +1801,404 @@ example.com {
         let doc: KdlDocument = cblt_file.parse()?;
         let config = build_config(&doc)?;
         println!("{:#?}", config);
+        match config["example.com"].first() {
+            Some(Directive::ReverseProxy { options, .. }) => {
+                let health = options.health.as_ref().ok_or("no health checks")?;
+                assert_eq!(health.uri, "/health");
+                assert_eq!(health.interval, Duration::from_secs(10));
+                assert_eq!(health.timeout, Duration::from_secs(2));
+                assert_eq!(health.status, (200, 299));
+                assert!(health.body.is_none());
+                assert_eq!((health.passes, health.fails), (1, 1));
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+
+        let doc: KdlDocument = r#""*:80" {
+    reverse_proxy "*" "http://b" {
+        health_uri "/ready?full=1"
+        health_status "3xx"
+        health_body "\"status\": ?\"up\""
+        health_passes "2"
+        health_fails "3"
+    }
+}"#
+        .parse()?;
+        match build_config(&doc)?["*:80"].first() {
+            Some(Directive::ReverseProxy { options, .. }) => {
+                let health = options.health.as_ref().ok_or("no health checks")?;
+                assert_eq!(health.uri, "/ready?full=1");
+                assert_eq!(health.status, (300, 399));
+                assert!(health
+                    .body
+                    .as_ref()
+                    .is_some_and(|r| r.is_match("{\"status\": \"up\"}")));
+                assert_eq!((health.passes, health.fails), (2, 3));
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+        let doc: KdlDocument = r#""*:80" { reverse_proxy "*" "http://b"; }"#.parse()?;
+        match build_config(&doc)?["*:80"].first() {
+            Some(Directive::ReverseProxy { options, .. }) => assert!(options.health.is_none()),
+            other => panic!("Unexpected directive {:?}", other),
+        }
+
+        for invalid in [
+            r#""*:80" { reverse_proxy "*" "http://b" { health_interval "5s"; }; }"#,
+            r#""*:80" { reverse_proxy "*" "http://b" { health_uri "health"; }; }"#,
+            r#""*:80" { reverse_proxy "*" "http://b" { health_uri "/h"; health_interval "0s"; }; }"#,
+            r#""*:80" { reverse_proxy "*" "http://b" { health_uri "/h"; health_status "2xy"; }; }"#,
+        ] {
+            let doc: KdlDocument = invalid.parse()?;
+            assert!(build_config(&doc).is_err());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reverse_proxy_headers() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"example.com" {
+    reverse_proxy "/api/*" "http://backend1:8080" {
+        header_up "Host" "{upstream_hostport}"
+        header_up "X-Real-IP" "{remote_ip}"
+        header_up "-X-Debug"
+        header_down "-Server"
+        header_down "+Via" "cblt"
+    }
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        println!("{:#?}", config);
+        match config["example.com"].first() {
+            Some(Directive::ReverseProxy { options, .. }) => {
+                assert_eq!(
+                    options.header_up,
+                    vec![
+                        HeaderOperation::Set(
+                            HeaderName::from_static("host"),
+                            HeaderValue::from_static("{upstream_hostport}")
+                        ),
+                        HeaderOperation::Set(
+                            HeaderName::from_static("x-real-ip"),
+                            HeaderValue::from_static("{remote_ip}")
+                        ),
+                        HeaderOperation::Remove(HeaderName::from_static("x-debug")),
+                    ]
+                );
+                assert_eq!(
+                    options.header_down,
+                    vec![
+                        HeaderOperation::Remove(HeaderName::from_static("server")),
+                        HeaderOperation::Add(
+                            HeaderName::from_static("via"),
+                            HeaderValue::from_static("cblt")
+                        ),
+                    ]
+                );
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+
+        let doc: KdlDocument =
+            r#""*:80" { reverse_proxy "*" "http://b:80" { header_up; }; }"#.parse()?;
+        assert!(build_config(&doc).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_trusted_proxies() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"example.com" {
+    reverse_proxy "/api/*" "http://backend1:8080" {
+        trusted_proxies "10.0.0.0/8" "192.168.1.7" "fd00::/8"
+    }
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        println!("{:#?}", config);
+        match config["example.com"].first() {
+            Some(Directive::ReverseProxy { options, .. }) => {
+                let trusted = |ip: &str| -> Result<bool, Box<dyn Error>> {
+                    let ip = ip.parse()?;
+                    Ok(options
+                        .trusted_proxies
+                        .iter()
+                        .any(|cidr| cidr.contains(&ip)))
+                };
+                assert!(trusted("10.200.3.4")?);
+                assert!(trusted("::ffff:10.0.0.1")?);
+                assert!(trusted("192.168.1.7")?);
+                assert!(!trusted("192.168.1.8")?);
+                assert!(trusted("fd12::1")?);
+                assert!(!trusted("fe80::1")?);
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+
+        assert!(IpCidr::parse("0.0.0.0/0")?.contains(&"8.8.8.8".parse()?));
+        assert!(IpCidr::parse("10.0.0.0/33").is_err());
+        assert!(IpCidr::parse("10.0.0/8").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_upstream_tls() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"example.com" {
+    reverse_proxy "/api/*" "https://10.0.0.5:8443" {
+        tls_ca "/etc/ssl/internal-ca.pem"
+        tls_server_name "backend.internal"
+        tls_client_cert "client.crt" "client.key"
+        tls_insecure_skip_verify
+    }
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        println!("{:#?}", config);
+        match config["example.com"].first() {
+            Some(Directive::ReverseProxy { options, .. }) => {
+                assert_eq!(options.tls.ca.as_deref(), Some("/etc/ssl/internal-ca.pem"));
+                assert_eq!(options.tls.server_name.as_deref(), Some("backend.internal"));
+                assert_eq!(
+                    options.tls.client_cert,
+                    Some(("client.crt".to_string(), "client.key".to_string()))
+                );
+                assert!(options.tls.insecure_skip_verify);
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+
+        let doc: KdlDocument =
+            r#""*:80" { reverse_proxy "*" "https://b" { tls_client_cert "c.crt"; }; }"#.parse()?;
+        assert!(build_config(&doc).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_path_rewrite() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"example.com" {
+    reverse_proxy "/api/*" "http://10.0.0.5:8080" {
+        strip_prefix "/api/"
+        rewrite "^/v1/(.*)$" "/$1"
+        add_prefix "/internal"
+    }
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        println!("{:#?}", config);
+        match config["example.com"].first() {
+            Some(Directive::ReverseProxy { options, .. }) => {
+                assert_eq!(options.strip_prefix.as_deref(), Some("/api"));
+                assert_eq!(options.add_prefix.as_deref(), Some("/internal"));
+                assert_eq!(options.rewrite.len(), 1);
+                assert_eq!(options.rewrite[0].regex.as_str(), "^/v1/(.*)$");
+                assert_eq!(options.rewrite[0].replacement, "/$1");
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+
+        for invalid in [
+            r#""*:80" { reverse_proxy "*" "http://b" { strip_prefix "api"; }; }"#,
+            r#""*:80" { reverse_proxy "*" "http://b" { rewrite "^/(" "/"; }; }"#,
+            r#""*:80" { reverse_proxy "*" "http://b" { rewrite "^/a"; }; }"#,
+        ] {
+            let doc: KdlDocument = invalid.parse()?;
+            assert!(build_config(&doc).is_err());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_keepalive() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"example.com" {
+    reverse_proxy "/api/*" "http://10.0.0.5:8080" {
+        keepalive_max_idle "8"
+        keepalive_idle_timeout "30s"
+        keepalive_max_lifetime "10m"
+    }
+    reverse_proxy "/*" "http://10.0.0.6:8080"
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        println!("{:#?}", config);
+        let keepalive: Vec<_> = config["example.com"]
+            .iter()
+            .filter_map(|d| match d {
+                Directive::ReverseProxy { options, .. } => Some(options.keepalive.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(keepalive[0].max_idle, 8);
+        assert_eq!(keepalive[0].idle_timeout, Duration::from_secs(30));
+        assert_eq!(keepalive[0].max_lifetime, Some(Duration::from_secs(600)));
+        assert_eq!(keepalive[1].max_idle, 32);
+        assert_eq!(keepalive[1].idle_timeout, Duration::from_secs(60));
+        assert_eq!(keepalive[1].max_lifetime, None);
+
+        let doc: KdlDocument =
+            r#""*:80" { reverse_proxy "*" "http://b" { keepalive_max_idle "many"; }; }"#.parse()?;
+        assert!(build_config(&doc).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_passive_health() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"example.com" {
+    reverse_proxy "/api/*" "http://10.0.0.5:8080" {
+        fail_window "1m"
+        fail_max "5"
+        fail_cooldown "10s"
+        fail_status "500" "503"
+        response_timeout "15s"
+    }
+    reverse_proxy "/x/*" "http://10.0.0.6:8080" {
+        fail_max "2"
+    }
+    reverse_proxy "/*" "http://10.0.0.7:8080"
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        println!("{:#?}", config);
+        let options: Vec<_> = config["example.com"]
+            .iter()
+            .filter_map(|d| match d {
+                Directive::ReverseProxy { options, .. } => Some(options.clone()),
+                _ => None,
+            })
+            .collect();
+        let passive = options[0]
+            .passive_health
+            .as_ref()
+            .ok_or("no passive checks")?;
+        assert_eq!(passive.window, Duration::from_secs(60));
+        assert_eq!(passive.max_fails, 5);
+        assert_eq!(passive.cooldown, Duration::from_secs(10));
+        assert_eq!(passive.status, vec![(500, 500), (503, 503)]);
+        assert_eq!(options[0].response_timeout, Some(Duration::from_secs(15)));
+        let passive = options[1]
+            .passive_health
+            .as_ref()
+            .ok_or("no passive checks")?;
+        assert_eq!(passive.window, Duration::from_secs(30));
+        assert_eq!(passive.max_fails, 2);
+        assert_eq!(passive.status, vec![(502, 504)]);
+        assert_eq!(options[1].response_timeout, None);
+        assert!(options[2].passive_health.is_none());
+
+        for invalid in [
+            r#""*:80" { reverse_proxy "*" "http://b" { fail_window "0s"; }; }"#,
+            r#""*:80" { reverse_proxy "*" "http://b" { fail_max; }; }"#,
+            r#""*:80" { reverse_proxy "*" "http://b" { fail_status "5x"; }; }"#,
+            r#""*:80" { reverse_proxy "*" "http://b" { response_timeout "soon"; }; }"#,
+        ] {
+            let doc: KdlDocument = invalid.parse()?;
+            assert!(build_config(&doc).is_err());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lb_policy() -> Result<(), Box<dyn Error>> {
+        let policy = |name: &str| -> Result<Option<LoadBalancePolicy>, Box<dyn Error>> {
+            let cblt_file = format!(
+                r#""*:80" {{ reverse_proxy "*" "http://b" {{ lb_policy "{}"; }}; }}"#,
+                name
+            );
+            let doc: KdlDocument = cblt_file.parse()?;
+            match build_config(&doc)?["*:80"].first() {
+                Some(Directive::ReverseProxy { options, .. }) => Ok(options.lb_policy.clone()),
+                other => Err(format!("Unexpected directive {:?}", other).into()),
+            }
+        };
+        assert!(matches!(
+            policy("round_robin")?,
+            Some(LoadBalancePolicy::RoundRobin)
+        ));
+        assert!(matches!(
+            policy("ip_hash")?,
+            Some(LoadBalancePolicy::IPHash)
+        ));
+        assert!(matches!(
+            policy("least_conn")?,
+            Some(LoadBalancePolicy::LeastConn)
+        ));
+        assert!(matches!(policy("random")?, Some(LoadBalancePolicy::Random)));
+        assert!(matches!(
+            policy(r#"random_choose" "3"#)?,
+            Some(LoadBalancePolicy::RandomChoose(3))
+        ));
+        assert!(matches!(
+            policy("random_choose")?,
+            Some(LoadBalancePolicy::RandomChoose(2))
+        ));
+        assert!(policy(r#"random_choose" "0"#).is_err());
+        assert!(policy("fewest").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_weighted_destinations() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"example.com" {
+    reverse_proxy "/api/*" "http://big:8080" weight=3 "http://small:8080" "http://mid:8080" weight="2" {
+        lb_policy "round_robin"
+    }
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        println!("{:#?}", config);
+        match config["example.com"].first() {
+            Some(Directive::ReverseProxy {
+                pattern,
+                destinations,
+                ..
+            }) => {
+                assert_eq!(pattern, "/api/*");
+                assert_eq!(
+                    destinations,
+                    &vec![
+                        Destination {
+                            url: "http://big:8080".to_string(),
+                            weight: 3
+                        },
+                        Destination::new("http://small:8080"),
+                        Destination {
+                            url: "http://mid:8080".to_string(),
+                            weight: 2
+                        },
+                    ]
+                );
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+
+        for invalid in [
+            r#""*:80" { reverse_proxy "*" weight=2 "http://b"; }"#,
+            r#""*:80" { reverse_proxy "*" "http://b" weight=0; }"#,
+            r#""*:80" { reverse_proxy "*" "http://b" weight="heavy"; }"#,
+            r#""*:80" { reverse_proxy "*" "http://b" priority=1; }"#,
+        ] {
+            let doc: KdlDocument = invalid.parse()?;
+            assert!(build_config(&doc).is_err());
+        }
 
         Ok(())
     }
@@ -550,6 +2219,28 @@ example.com {
         let doc: KdlDocument = cblt_file.parse()?;
         let config = build_config(&doc)?;
         println!("{:#?}", config);
+        match config["example.com"].first() {
+            Some(Directive::ReverseProxy { options, .. }) => match &options.lb_policy {
+                Some(LoadBalancePolicy::Cookie(cookie)) => {
+                    assert_eq!(cookie.name, "my_sticky_cookie");
+                    assert_eq!(cookie.path, "/");
+                    assert_eq!(cookie.max_age, Some(3600));
+                    assert!(cookie.secret.is_none());
+                }
+                other => panic!("Unexpected lb_policy {:?}", other),
+            },
+            other => panic!("Unexpected directive {:?}", other),
+        }
+
+        for invalid in [
+            r#""*:80" { reverse_proxy "*" "http://b" { lb_policy "cookie" { lb_cookie_name "a b"; }; }; }"#,
+            r#""*:80" { reverse_proxy "*" "http://b" { lb_policy "cookie" { lb_cookie_path "api"; }; }; }"#,
+            r#""*:80" { reverse_proxy "*" "http://b" { lb_policy "cookie" { lb_cookie_max_age "long"; }; }; }"#,
+            r#""*:80" { reverse_proxy "*" "http://b" { lb_policy "cookie" { lb_cookie_domain "x"; }; }; }"#,
+        ] {
+            let doc: KdlDocument = invalid.parse()?;
+            assert!(build_config(&doc).is_err());
+        }
 
         Ok(())
     }
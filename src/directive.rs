@@ -1,15 +1,22 @@
-use crate::config::Directive;
+use crate::config::{Directive, EncodeOptions, HeaderOperation, RequestLimits};
 use crate::error::CbltError;
-use crate::request::{socket_to_request, BUF_SIZE};
-use crate::response::{error_response, log_request_response, send_response};
-use crate::server::ServerSettings;
+use crate::request::{
+    check_request_limits, is_keep_alive, read_request_body, socket_to_request, BUF_SIZE,
+};
+use crate::response::{
+    error_response, log_request_response, respond, send_response, ResponseHeaders,
+};
+use crate::server::{ConnectionOptions, HostDetails, ServerSettings};
 use crate::{file_server, matches_pattern, reverse_proxy};
 use bytes::BytesMut;
-use http::{Response, StatusCode};
-use log::{debug, error};
+use http::header::CONNECTION;
+use http::uri::Scheme;
+use http::{HeaderValue, Request, Response, StatusCode};
+use log::{debug, error, info};
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::timeout;
 #[cfg(feature = "trace")]
 use tracing::instrument;
 
@@ -18,233 +25,404 @@ pub async fn directive_process<S>(
     socket: &mut S,
     settings: Arc<ServerSettings>,
     addr: SocketAddr,
+    options: &ConnectionOptions,
 ) -> Result<(), CbltError>
 where
     S: AsyncReadExt + AsyncWriteExt + Unpin,
 {
     let mut buffer = BytesMut::with_capacity(BUF_SIZE);
-    match socket_to_request(socket, &mut buffer).await {
-        Err(err) => {
-            let response = error_response(StatusCode::BAD_REQUEST);
-            let ret = send_response(socket, response?).await;
-            match ret {
-                Ok(()) => {}
-                Err(err) => {
-                    #[cfg(debug_assertions)]
-                    error!("Error: {}", err);
-                    return Err(err);
+    let mut requests_served = 0;
+    loop {
+        // Between requests the connection may stay idle up to the keep-alive timeout,
+        // once the next request starts its headers have to arrive within the header timeout
+        if requests_served > 0 && buffer.is_empty() {
+            match timeout(options.keep_alive_timeout, socket.read_buf(&mut buffer)).await {
+                Ok(Ok(0)) => return Ok(()),
+                Ok(Ok(_)) => {}
+                Ok(Err(err)) => return Err(err.into()),
+                Err(_) => {
+                    info!("Idle timeout: {}", addr);
+                    return Ok(());
                 }
             }
-            Err(err)
         }
-        Ok(request) => {
-            let host = match request.headers().get("Host") {
-                Some(h) => h.to_str().unwrap_or(""),
-                None => "",
-            };
-
-            // find host starting with "*"
-            let cfg_opt = settings.hosts.iter().find(|(k, _)| k.starts_with("*"));
-            let host_config = match cfg_opt {
-                None => {
-                    let host_config = match settings.hosts.get(host) {
-                        Some(cfg) => cfg,
-                        None => {
-                            let response = error_response(StatusCode::FORBIDDEN);
-                            let _ = send_response(socket, response?).await;
-                            return Err(CbltError::ResponseError {
-                                details: "Forbidden".to_string(),
-                                status_code: StatusCode::FORBIDDEN,
-                            });
-                        }
-                    };
-                    host_config
-                }
-                Some((_, cfg)) => cfg,
-            };
-
-            let mut root_path: Option<&str> = None;
-            let mut fallback_file: Option<&str> = None;
-
-            for directive in &host_config.directives {
-                match directive {
-                    Directive::Root {
-                        pattern,
-                        path,
-                        fallback,
-                    } => {
-                        #[cfg(debug_assertions)]
-                        debug!("Root: {} -> {}", pattern, path);
-                        if matches_pattern(pattern.as_str(), request.uri().path()) {
-                            root_path = Some(path.as_str());
-                            fallback_file = fallback.as_deref();
-                        }
-                    }
-                    Directive::FileServer => {
-                        #[cfg(debug_assertions)]
-                        debug!("File server with fallback: {:?}", fallback_file);
-                        let ret = file_server::file_directive(
-                            root_path.as_deref(),
-                            fallback_file,
-                            &request,
-                            socket,
-                        )
-                        .await;
-                        match ret {
-                            Ok(_) => {
-                                log_request_response(&request, StatusCode::OK);
-                                return Ok(());
-                            }
-                            Err(error) => match error {
-                                CbltError::ResponseError {
-                                    details: _,
-                                    status_code,
-                                } => {
-                                    let response = error_response(status_code);
-                                    match send_response(socket, response?).await {
-                                        Ok(()) => {
-                                            log_request_response(&request, status_code);
-                                            return Ok(());
-                                        }
-                                        Err(err) => {
-                                            log_request_response(
-                                                &request,
-                                                StatusCode::INTERNAL_SERVER_ERROR,
-                                            );
-                                            return Err(err);
-                                        }
-                                    }
-                                }
-                                CbltError::DirectiveNotMatched => {}
-                                err => {
-                                    log_request_response(
-                                        &request,
-                                        StatusCode::INTERNAL_SERVER_ERROR,
-                                    );
-                                    return Err(err);
-                                }
-                            },
-                        }
-                        break;
-                    }
-                    Directive::ReverseProxy {
-                        pattern,
-                        destinations,
-                        ..
-                    } => {
-                        #[cfg(debug_assertions)]
-                        debug!("Reverse proxy: {} -> {:?}", pattern, destinations);
-                        match reverse_proxy::proxy_directive(
-                            &request,
-                            socket,
-                            &host_config.reverse_proxy_states,
-                            addr,
-                            directive,
-                        )
-                        .await
-                        {
-                            Ok(status) => {
-                                log_request_response(&request, status);
-                                return Ok(());
-                            }
-                            Err(err) => match err {
-                                CbltError::DirectiveNotMatched => {}
-                                CbltError::ResponseError {
-                                    details: _,
-                                    status_code,
-                                } => {
-                                    let response = error_response(status_code);
-                                    match send_response(socket, response?).await {
-                                        Ok(()) => {
-                                            log_request_response(&request, status_code);
-                                            return Ok(());
-                                        }
-                                        Err(err) => {
-                                            log_request_response(
-                                                &request,
-                                                StatusCode::INTERNAL_SERVER_ERROR,
-                                            );
-                                            return Err(err);
-                                        }
-                                    }
-                                }
-                                other => {
-                                    log_request_response(
-                                        &request,
-                                        StatusCode::INTERNAL_SERVER_ERROR,
-                                    );
-                                    return Err(other);
-                                }
-                            },
-                        }
-                    }
-                    Directive::Redir { destination } => {
-                        let dest = destination.replace("{uri}", request.uri().path());
-                        let response = Response::builder()
-                            .status(StatusCode::FOUND)
-                            .header("Location", &dest)
-                            .body(BytesMut::new())?; // Empty body for redirects?
-                                                     //
-                        match send_response(socket, response).await {
-                            Ok(_) => {
-                                log_request_response(&request, StatusCode::FOUND);
-                                return Ok(());
-                            }
-                            Err(err) => {
-                                log_request_response(&request, StatusCode::INTERNAL_SERVER_ERROR);
-                                return Err(err);
-                            }
-                        }
-                    }
-                    Directive::RedirIfNotCookie {
-                        cookiename,
-                        destination,
-                    } => {
-                        let dest = destination.replace("{uri}", request.uri().path());
-                        let response = Response::builder()
-                            .status(StatusCode::FOUND)
-                            .header("Location", &dest)
-                            .body(BytesMut::new())?; // Empty body for redirects?
-                                                     //
-                        let cookies = match request.headers().get("Cookie") {
-                            Some(cookies) => cookies.to_str().unwrap_or(""),
-                            None => "",
-                        };
-
-                        match cookies
-                            .split(';')
-                            .collect::<Vec<&str>>()
-                            .iter()
-                            .find(|&x| x.contains(cookiename))
-                        {
-                            Some(_) => debug!("Cookie found: {}", cookiename),
-                            None => match send_response(socket, response).await {
-                                Ok(_) => {
-                                    log_request_response(&request, StatusCode::FOUND);
-                                    return Ok(());
-                                }
-                                Err(err) => {
-                                    log_request_response(
-                                        &request,
-                                        StatusCode::INTERNAL_SERVER_ERROR,
-                                    );
-                                    return Err(err);
-                                }
-                            },
-                        };
-                    }
 
-                    Directive::TlS { .. } => {}
+        let read_result = match timeout(
+            options.header_timeout,
+            socket_to_request(socket, &mut buffer, &options.limits),
+        )
+        .await
+        {
+            Ok(read_result) => read_result,
+            Err(_) => {
+                info!("Header timeout: {}", addr);
+                if buffer.is_empty() {
+                    return Ok(());
                 }
+                return reject(socket, request_timeout("Header timeout")).await;
             }
+        };
+
+        match read_result {
+            Ok(None) => return Ok(()),
+            Err(err) => return reject(socket, err).await,
+            Ok(Some((mut request, framing))) => {
+                let limits = host_limits(&settings, &request, &options.limits);
+                let body_result = match check_request_limits(&request, framing, &limits) {
+                    Ok(()) => timeout(
+                        options.body_timeout,
+                        read_request_body(socket, &mut buffer, &mut request, framing, &limits),
+                    )
+                    .await
+                    .unwrap_or_else(|_| {
+                        info!("Body timeout: {}", addr);
+                        Err(request_timeout("Body timeout"))
+                    }),
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = body_result {
+                    return reject(socket, err).await;
+                }
 
-            let response = error_response(StatusCode::NOT_FOUND);
-            if let Err(err) = send_response(socket, response?).await {
-                log_request_response(&request, StatusCode::INTERNAL_SERVER_ERROR);
-                return Err(err);
+                requests_served += 1;
+                if requests_served >= options.max_requests {
+                    request
+                        .headers_mut()
+                        .insert(CONNECTION, HeaderValue::from_static("close"));
+                }
+                let keep_alive = is_keep_alive(&request);
+                request_process(socket, &settings, addr, &mut request).await?;
+                if !keep_alive {
+                    return Ok(());
+                }
             }
-            log_request_response(&request, StatusCode::NOT_FOUND);
+        }
+    }
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn request_timeout(details: &str) -> CbltError {
+    CbltError::RequestError {
+        details: details.to_string(),
+        status_code: StatusCode::REQUEST_TIMEOUT,
+    }
+}
+
+/// Answers a request that could not be read and closes the connection
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn reject<S>(socket: &mut S, err: CbltError) -> Result<(), CbltError>
+where
+    S: AsyncWriteExt + Unpin,
+{
+    let status_code = match &err {
+        CbltError::RequestError { status_code, .. }
+        | CbltError::ResponseError { status_code, .. } => *status_code,
+        _ => StatusCode::BAD_REQUEST,
+    };
+    let mut response = error_response(status_code)?;
+    response
+        .headers_mut()
+        .insert(CONNECTION, HeaderValue::from_static("close"));
+    let ret = send_response(socket, response).await;
+    match ret {
+        Ok(()) => {}
+        Err(err) => {
+            #[cfg(debug_assertions)]
+            error!("Error: {}", err);
+            return Err(err);
+        }
+    }
+    Err(err)
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn find_host<'a>(
+    settings: &'a ServerSettings,
+    request: &Request<BytesMut>,
+) -> Option<&'a HostDetails> {
+    let host = match request.headers().get("Host") {
+        Some(h) => h.to_str().unwrap_or(""),
+        None => "",
+    };
+
+    // find host starting with "*"
+    let cfg_opt = settings.hosts.iter().find(|(k, _)| k.starts_with("*"));
+    match cfg_opt {
+        None => settings.hosts.get(host),
+        Some((_, cfg)) => Some(cfg),
+    }
+}
+
+/// Global limits with the overrides of the requested host applied
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn host_limits(
+    settings: &ServerSettings,
+    request: &Request<BytesMut>,
+    limits: &RequestLimits,
+) -> RequestLimits {
+    find_host(settings, request)
+        .and_then(|host_config| {
+            host_config.directives.iter().find_map(|d| match d {
+                Directive::Limits { options } => Some(limits.merge(options)),
+                _ => None,
+            })
+        })
+        .unwrap_or_else(|| limits.clone())
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn request_process<S>(
+    socket: &mut S,
+    settings: &ServerSettings,
+    addr: SocketAddr,
+    request: &mut Request<BytesMut>,
+) -> Result<(), CbltError>
+where
+    S: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    let host_config = match find_host(settings, request) {
+        Some(cfg) => cfg,
+        None => {
+            let response = error_response(StatusCode::FORBIDDEN);
+            let _ = respond(socket, request, response?).await;
+            return Err(CbltError::ResponseError {
+                details: "Forbidden".to_string(),
+                status_code: StatusCode::FORBIDDEN,
+            });
+        }
+    };
+
+    // Every response to the request picks these up in `finish_headers`
+    let operations: Vec<HeaderOperation> = host_config
+        .directives
+        .iter()
+        .filter_map(|d| match d {
+            Directive::Header {
+                pattern,
+                operations,
+            } if matches_pattern(pattern.as_str(), request.uri().path()) => Some(operations),
+            _ => None,
+        })
+        .flatten()
+        .cloned()
+        .collect();
+    if !operations.is_empty() {
+        request.extensions_mut().insert(ResponseHeaders(operations));
+    }
+    // Scheme of the client connection for the forwarded headers of reverse_proxy
+    request
+        .extensions_mut()
+        .insert(if settings.tls_acceptor.is_some() {
+            Scheme::HTTPS
+        } else {
+            Scheme::HTTP
+        });
+    let request = &*request;
+
+    let result = match host_process(socket, host_config, addr, request).await {
+        Err(CbltError::ResponseError { status_code, .. }) => {
+            error_process(socket, host_config, addr, request, status_code).await
+        }
+        result => result,
+    };
+    match result {
+        Ok(status) => {
+            log_request_response(request, status);
             Ok(())
         }
+        Err(err) => {
+            log_request_response(request, StatusCode::INTERNAL_SERVER_ERROR);
+            Err(err)
+        }
     }
 }
+
+/// Runs the directives of a host until one of them responds. Error statuses come back
+/// as `ResponseError` with nothing sent yet, so `error_process` can answer them.
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn host_process<S>(
+    socket: &mut S,
+    host_config: &HostDetails,
+    addr: SocketAddr,
+    request: &Request<BytesMut>,
+) -> Result<StatusCode, CbltError>
+where
+    S: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    let mut root_path: Option<&str> = None;
+    let mut fallback_file: Option<&str> = None;
+
+    for directive in &host_config.directives {
+        match directive {
+            Directive::Root {
+                pattern,
+                path,
+                fallback,
+            } => {
+                #[cfg(debug_assertions)]
+                debug!("Root: {} -> {}", pattern, path);
+                if matches_pattern(pattern.as_str(), request.uri().path()) {
+                    root_path = Some(path.as_str());
+                    fallback_file = fallback.as_deref();
+                }
+            }
+            Directive::FileServer { options } => {
+                #[cfg(debug_assertions)]
+                debug!("File server with fallback: {:?}", fallback_file);
+                let default_encode = EncodeOptions::default();
+                let encode = host_config
+                    .directives
+                    .iter()
+                    .find_map(|d| match d {
+                        Directive::Encode { options } => Some(options),
+                        _ => None,
+                    })
+                    .unwrap_or(&default_encode);
+                match file_server::file_directive(
+                    root_path,
+                    fallback_file,
+                    options,
+                    encode,
+                    request,
+                    socket,
+                )
+                .await
+                {
+                    Err(CbltError::DirectiveNotMatched) => {}
+                    ret => return ret,
+                }
+                break;
+            }
+            Directive::ReverseProxy {
+                pattern,
+                destinations,
+                ..
+            } => {
+                #[cfg(debug_assertions)]
+                debug!("Reverse proxy: {} -> {:?}", pattern, destinations);
+                match reverse_proxy::proxy_directive(
+                    request,
+                    socket,
+                    &host_config.reverse_proxy_states,
+                    addr,
+                    directive,
+                )
+                .await
+                {
+                    Err(CbltError::DirectiveNotMatched) => {}
+                    ret => return ret,
+                }
+            }
+            Directive::Redir { destination } => {
+                let dest = destination.replace("{uri}", request.uri().path());
+                let response = Response::builder()
+                    .status(StatusCode::FOUND)
+                    .header("Location", &dest)
+                    .body(BytesMut::new())?; // Empty body for redirects?
+                                             //
+                respond(socket, request, response).await?;
+                return Ok(StatusCode::FOUND);
+            }
+            Directive::RedirIfNotCookie {
+                cookiename,
+                destination,
+            } => {
+                let dest = destination.replace("{uri}", request.uri().path());
+                let response = Response::builder()
+                    .status(StatusCode::FOUND)
+                    .header("Location", &dest)
+                    .body(BytesMut::new())?; // Empty body for redirects?
+                                             //
+                let cookies = match request.headers().get("Cookie") {
+                    Some(cookies) => cookies.to_str().unwrap_or(""),
+                    None => "",
+                };
+
+                match cookies
+                    .split(';')
+                    .collect::<Vec<&str>>()
+                    .iter()
+                    .find(|&x| x.contains(cookiename))
+                {
+                    Some(_) => debug!("Cookie found: {}", cookiename),
+                    None => {
+                        respond(socket, request, response).await?;
+                        return Ok(StatusCode::FOUND);
+                    }
+                };
+            }
+
+            Directive::TlS { .. }
+            | Directive::Protocols { .. }
+            | Directive::Limits { .. }
+            | Directive::Encode { .. }
+            | Directive::HandleErrors { .. }
+            | Directive::Header { .. } => {}
+        }
+    }
+
+    Err(CbltError::ResponseError {
+        details: "Not found".to_string(),
+        status_code: StatusCode::NOT_FOUND,
+    })
+}
+
+/// Answers an error status with the first `handle_errors` block of the host that handles it,
+/// or with the plain error response
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn error_process<S>(
+    socket: &mut S,
+    host_config: &HostDetails,
+    addr: SocketAddr,
+    request: &Request<BytesMut>,
+    status: StatusCode,
+) -> Result<StatusCode, CbltError>
+where
+    S: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    let handler = host_config
+        .directives
+        .iter()
+        .filter_map(|d| match d {
+            Directive::HandleErrors { options } => Some(options),
+            _ => None,
+        })
+        .zip(&host_config.error_handlers)
+        .find(|(options, _)| options.handles(status));
+
+    if let Some((options, handler)) = handler {
+        let ret = match options.page(status) {
+            Some(page) => {
+                // Pages live under the root of the block, or else under the root of the host
+                let root = root_path(&handler.directives, request)
+                    .or_else(|| root_path(&host_config.directives, request));
+                file_server::error_page(root, page, status, request, socket).await
+            }
+            None => host_process(socket, handler, addr, request).await,
+        };
+        match ret {
+            // A failing handler falls back to the plain response of the original status
+            Err(CbltError::ResponseError { .. }) => {}
+            ret => return ret,
+        }
+    }
+
+    respond(socket, request, error_response(status)?).await?;
+    Ok(status)
+}
+
+/// Path of the last root directive that matches the request
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn root_path<'a>(directives: &'a [Directive], request: &Request<BytesMut>) -> Option<&'a str> {
+    directives
+        .iter()
+        .filter_map(|d| match d {
+            Directive::Root { pattern, path, .. }
+                if matches_pattern(pattern.as_str(), request.uri().path()) =>
+            {
+                Some(path.as_str())
+            }
+            _ => None,
+        })
+        .next_back()
+}
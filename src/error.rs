@@ -59,6 +59,24 @@ pub enum CbltError {
         #[from]
         source: http::header::ToStrError,
     },
+    // from http::header::InvalidHeaderValue
+    #[error("InvalidHeaderValue: {source:?}")]
+    InvalidHeaderValue {
+        #[from]
+        source: http::header::InvalidHeaderValue,
+    },
+    // from http::header::InvalidHeaderName
+    #[error("InvalidHeaderName: {source:?}")]
+    InvalidHeaderName {
+        #[from]
+        source: http::header::InvalidHeaderName,
+    },
+    // from h2::Error
+    #[error("H2Error: {source:?}")]
+    H2Error {
+        #[from]
+        source: h2::Error,
+    },
     // from KdlError
     #[error("KdlError: {source:?}")]
     KdlError {
@@ -83,9 +101,17 @@ pub enum CbltError {
         #[from]
         source: DurationError,
     },
+    // from regex::Error
+    #[error("RegexError: {source:?}")]
+    RegexError {
+        #[from]
+        source: regex::Error,
+    },
 
     #[error("KdlParseError: {details:?}")]
     KdlParseError { details: String },
+    #[error("InvalidSize: {details:?}")]
+    InvalidSize { details: String },
     #[error("HeaplessError")]
     HeaplessError,
     #[error("ServiceNameNotFound")]
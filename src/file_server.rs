@@ -1,24 +1,41 @@
+use crate::browse;
+use crate::compression::{is_compressible, negotiate, preferred_encodings};
+use crate::config::{EncodeOptions, Encoding, FileServerOptions};
 use crate::error::CbltError;
 use crate::request::parse_range_header;
-use crate::response::{ranged_file_response, send_response_file};
+use crate::response::{
+    finish_headers, multipart_ranged_file_response, ranged_file_response, respond,
+    send_multipart_response, send_response, send_response_file,
+};
 use bytes::BytesMut;
-use http::header::RANGE;
-use http::{Request, Response, StatusCode};
+use http::header::{
+    CONTENT_ENCODING, CONTENT_TYPE, ETAG, IF_MATCH, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE,
+    IF_UNMODIFIED_SINCE, LAST_MODIFIED, LOCATION, RANGE, VARY,
+};
+use http::{HeaderMap, HeaderName, HeaderValue, Method, Request, Response, StatusCode};
+use httpdate::{fmt_http_date, parse_http_date};
+use std::fs::Metadata;
 use std::path::{Component, Path, PathBuf};
-use tokio::fs::File;
-use tokio::io::AsyncWrite;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
 #[cfg(feature = "trace")]
 use tracing::instrument;
 
+/// Upper bound of ranges served in one multipart/byteranges response, after coalescing
+pub const MAX_RANGES: usize = 16;
+
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
 pub async fn file_directive<S>(
     root_path: Option<&str>,
     fallback_file: Option<&str>, // fallback file path
+    options: &FileServerOptions,
+    encode: &EncodeOptions,
     request: &Request<BytesMut>,
     socket: &mut S,
 ) -> Result<StatusCode, CbltError>
 where
-    S: AsyncWrite + Unpin,
+    S: AsyncWriteExt + Unpin,
 {
     match root_path {
         None => Err(CbltError::ResponseError {
@@ -26,96 +43,481 @@ where
             status_code: StatusCode::INTERNAL_SERVER_ERROR,
         }),
         Some(root) => {
-            if let Some(mut file_path) = sanitize_path(
-                Path::new(root),
-                request.uri().path().trim_start_matches('/'),
-            ) {
-                if file_path.is_dir() {
-                    file_path.push("index.html");
+            let request_path =
+                percent_decode(request.uri().path()).ok_or_else(|| CbltError::ResponseError {
+                    details: "Invalid path encoding".to_string(),
+                    status_code: StatusCode::BAD_REQUEST,
+                })?;
+            let file_path = match resolve_path(Path::new(root), &request_path, options) {
+                Resolution::NotMatched => return Err(CbltError::DirectiveNotMatched),
+                Resolution::Browse(dir_path) => {
+                    return browse::browse_directive(&dir_path, request, socket).await;
+                }
+                Resolution::Redirect(add_slash) => {
+                    return redirect(socket, request, add_slash).await;
                 }
+                Resolution::File(file_path) => Some(file_path),
+                Resolution::NotFound => None,
+            };
+
+            // try to open the requested file
+            let file_result = match &file_path {
+                Some(file_path) => File::open(file_path).await.ok(),
+                None => None,
+            };
 
-                // try to open the requested file
-                let file_result = File::open(&file_path).await;
-
-                let (file, final_path) =
-                    match file_result {
-                        Ok(file) => (file, file_path),
-                        Err(_) => {
-                            // if it fails, check for the fallback file
-                            if let Some(fallback) = fallback_file {
-                                let fallback_path =
-                                    Path::new(root).join(fallback.trim_start_matches('/'));
-                                match File::open(&fallback_path).await {
-                                    Ok(fallback_file) => (fallback_file, fallback_path),
-                                    Err(err) => return Err(CbltError::ResponseError {
-                                        details: format!(
-                                            "Neither requested file nor fallback file found: {}",
-                                            err
-                                        ),
-                                        status_code: StatusCode::NOT_FOUND,
-                                    }),
-                                }
-                            } else {
+            let (file, final_path) = match (file_result, file_path) {
+                (Some(file), Some(file_path)) => (file, file_path),
+                _ => {
+                    // if it fails, check for the fallback file
+                    if let Some(fallback) = fallback_file {
+                        let fallback_path = Path::new(root).join(fallback.trim_start_matches('/'));
+                        match File::open(&fallback_path).await {
+                            Ok(fallback_file) => (fallback_file, fallback_path),
+                            Err(err) => {
                                 return Err(CbltError::ResponseError {
-                                    details: "File not found".to_string(),
+                                    details: format!(
+                                        "Neither requested file nor fallback file found: {}",
+                                        err
+                                    ),
                                     status_code: StatusCode::NOT_FOUND,
-                                });
+                                })
                             }
                         }
-                    };
+                    } else {
+                        return Err(CbltError::ResponseError {
+                            details: "File not found".to_string(),
+                            status_code: StatusCode::NOT_FOUND,
+                        });
+                    }
+                }
+            };
 
-                let content_length = file_size(&file).await?;
+            // A precompressed sidecar replaces the file, validators and length included
+            let (file, content_encoding) =
+                match open_precompressed(&final_path, request, &options.precompressed).await {
+                    Some((sidecar, encoding)) => (sidecar, Some(encoding)),
+                    None => (file, None),
+                };
 
-                if let Some(range_header) = request.headers().get(RANGE) {
-                    let range_str =
-                        range_header
-                            .to_str()
-                            .map_err(|_| CbltError::ResponseError {
-                                details: "Invalid Range header".to_string(),
-                                status_code: StatusCode::BAD_REQUEST,
-                            })?;
+            let metadata = file.metadata().await?;
+            let content_length = metadata.len();
+            let mut validators = Validators::from_metadata(&metadata);
+            if let Some(encoding) = content_encoding {
+                // Sidecars of the same size and age must not share an ETag
+                validators.etag = format!(
+                    "{}-{}\"",
+                    validators.etag.trim_end_matches('"'),
+                    encoding.extension()
+                );
+            }
+            let mime_compressible = is_compressible(mime_type(&final_path).as_str());
+            let compressible = content_encoding.is_none()
+                && !encode.encodings.is_empty()
+                && content_length >= encode.min_length
+                && mime_compressible;
+            // Whether the response depends on Accept-Encoding
+            let vary = compressible
+                || content_encoding.is_some()
+                || (!options.precompressed.is_empty() && mime_compressible);
+
+            match evaluate_preconditions(request, &validators) {
+                Precondition::Proceed => {}
+                Precondition::NotModified => {
+                    let mut response = Response::builder()
+                        .status(StatusCode::NOT_MODIFIED)
+                        .body(BytesMut::new())?;
+                    validators.insert_headers(response.headers_mut())?;
+                    vary_header(response.headers_mut(), vary);
+                    finish_headers(response.headers_mut(), request);
+                    send_response(socket, response).await?;
+                    return Ok(StatusCode::NOT_MODIFIED);
+                }
+                Precondition::Failed => {
+                    return Err(CbltError::ResponseError {
+                        details: "Precondition failed".to_string(),
+                        status_code: StatusCode::PRECONDITION_FAILED,
+                    });
+                }
+            }
 
-                    let range = parse_range_header(range_str, content_length)?;
+            let range_header = request
+                .headers()
+                .get(RANGE)
+                .filter(|_| if_range_matches(request, &validators));
+            if let Some(range_header) = range_header {
+                let range_str = range_header
+                    .to_str()
+                    .map_err(|_| CbltError::ResponseError {
+                        details: "Invalid Range header".to_string(),
+                        status_code: StatusCode::BAD_REQUEST,
+                    })?;
 
-                    let response =
-                        ranged_file_response(file, &final_path, content_length, range).await?;
-                    send_response_file(socket, response, request).await?;
+                let mut ranges = parse_range_header(range_str, content_length)?;
+
+                if ranges.len() == 1 {
+                    let mut response =
+                        ranged_file_response(file, &final_path, content_length, ranges.remove(0))
+                            .await?;
+                    validators.insert_headers(response.headers_mut())?;
+                    vary_header(response.headers_mut(), vary);
+                    // Ranges address bytes of the file, so they are never compressed
+                    send_response_file(socket, response, request, None).await?;
+                    Ok(StatusCode::PARTIAL_CONTENT)
+                } else if ranges.len() <= MAX_RANGES {
+                    let mut response =
+                        multipart_ranged_file_response(file, &final_path, content_length, ranges)?;
+                    validators.insert_headers(response.headers_mut())?;
+                    vary_header(response.headers_mut(), vary);
+                    send_multipart_response(socket, response, request).await?;
                     Ok(StatusCode::PARTIAL_CONTENT)
                 } else {
-                    let response = file_response(file, &final_path, content_length)?;
-                    send_response_file(socket, response, request).await?;
+                    // Too many ranges are answered with the whole file, as RFC 9110 allows
+                    let mut response = file_response(file, &final_path, content_length)?;
+                    validators.insert_headers(response.headers_mut())?;
+                    vary_header(response.headers_mut(), vary);
+                    let encoding = compressible.then(|| negotiate(request, encode)).flatten();
+                    send_response_file(socket, response, request, encoding).await?;
                     Ok(StatusCode::OK)
                 }
             } else {
-                Err(CbltError::DirectiveNotMatched)
+                let mut response = file_response(file, &final_path, content_length)?;
+                validators.insert_headers(response.headers_mut())?;
+                vary_header(response.headers_mut(), vary);
+                if let Some(encoding) = content_encoding {
+                    response.headers_mut().insert(
+                        CONTENT_ENCODING,
+                        HeaderValue::from_static(encoding.as_str()),
+                    );
+                }
+                let encoding = compressible.then(|| negotiate(request, encode)).flatten();
+                send_response_file(socket, response, request, encoding).await?;
+                Ok(StatusCode::OK)
+            }
+        }
+    }
+}
+
+/// Cache validators of a file, derived from its metadata
+struct Validators {
+    etag: String,
+    last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    fn from_metadata(metadata: &Metadata) -> Self {
+        // HTTP dates have a one second resolution
+        let modified_secs = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|duration| duration.as_secs());
+        Validators {
+            etag: format!("\"{:x}-{:x}\"", modified_secs.unwrap_or(0), metadata.len()),
+            last_modified: modified_secs.map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+        }
+    }
+
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    fn insert_headers(&self, headers: &mut HeaderMap) -> Result<(), CbltError> {
+        headers.insert(ETAG, HeaderValue::from_str(&self.etag)?);
+        if let Some(last_modified) = self.last_modified {
+            headers.insert(
+                LAST_MODIFIED,
+                HeaderValue::from_str(&fmt_http_date(last_modified))?,
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Precondition {
+    Proceed,
+    NotModified,
+    Failed,
+}
+
+/// Evaluates conditional request headers in the order of RFC 9110, section 13.2.2
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn evaluate_preconditions(request: &Request<BytesMut>, validators: &Validators) -> Precondition {
+    let headers = request.headers();
+    let is_get_or_head = request.method() == Method::GET || request.method() == Method::HEAD;
+
+    if let Some(if_match) = header_str(headers, IF_MATCH) {
+        if !etag_list_matches(if_match, &validators.etag, true) {
+            return Precondition::Failed;
+        }
+    } else if let Some(since) = header_date(headers, IF_UNMODIFIED_SINCE) {
+        if validators
+            .last_modified
+            .is_none_or(|modified| modified > since)
+        {
+            return Precondition::Failed;
+        }
+    }
+
+    if let Some(if_none_match) = header_str(headers, IF_NONE_MATCH) {
+        if etag_list_matches(if_none_match, &validators.etag, false) {
+            return if is_get_or_head {
+                Precondition::NotModified
+            } else {
+                Precondition::Failed
+            };
+        }
+    } else if is_get_or_head {
+        if let (Some(since), Some(modified)) = (
+            header_date(headers, IF_MODIFIED_SINCE),
+            validators.last_modified,
+        ) {
+            if modified <= since {
+                return Precondition::NotModified;
             }
         }
     }
+
+    Precondition::Proceed
 }
 
+/// A Range request is served only while the If-Range validator still matches the file
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
-async fn file_size(file: &File) -> Result<u64, CbltError> {
-    let metadata = file.metadata().await?;
-    Ok(metadata.len())
+fn if_range_matches(request: &Request<BytesMut>, validators: &Validators) -> bool {
+    let Some(if_range) = header_str(request.headers(), IF_RANGE) else {
+        return true;
+    };
+    let if_range = if_range.trim();
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        etag_matches(if_range, &validators.etag, true)
+    } else {
+        match (parse_http_date(if_range), validators.last_modified) {
+            (Ok(date), Some(modified)) => date == modified,
+            _ => false,
+        }
+    }
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn etag_list_matches(list: &str, etag: &str, strong: bool) -> bool {
+    list.trim() == "*"
+        || list
+            .split(',')
+            .any(|tag| etag_matches(tag.trim(), etag, strong))
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn etag_matches(tag: &str, etag: &str, strong: bool) -> bool {
+    if strong {
+        !tag.starts_with("W/") && tag == etag
+    } else {
+        tag.trim_start_matches("W/") == etag.trim_start_matches("W/")
+    }
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn header_str(headers: &HeaderMap, name: HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// Invalid dates are ignored, as if the header was not sent
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn header_date(headers: &HeaderMap, name: HeaderName) -> Option<SystemTime> {
+    header_str(headers, name).and_then(|value| parse_http_date(value.trim()).ok())
 }
 
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
 fn file_response(
     file: File,
-    file_path: &PathBuf,
+    file_path: &Path,
     content_length: u64,
 ) -> Result<Response<File>, CbltError> {
-    // Guess the MIME type based on the file extension
-    let mime_type = mime_guess::from_path(file_path)
-        .first_or_octet_stream()
-        .to_string();
     Ok(Response::builder()
         .status(StatusCode::OK)
         .header("Content-Length", content_length)
-        .header("Content-Type", mime_type)
+        .header("Content-Type", mime_type(file_path))
         .body(file)?)
 }
 
+#[derive(Debug, PartialEq, Eq)]
+enum Resolution {
+    NotMatched, // every candidate points outside of the root
+    File(PathBuf),
+    Browse(PathBuf),
+    Redirect(bool), // true adds a trailing slash, false strips it
+    NotFound,
+}
+
+/// Finds the file for a request path: every `try_files` candidate (just the path itself by
+/// default) is tried in order, directories resolve to their first existing index file
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn resolve_path(root: &Path, request_path: &str, options: &FileServerOptions) -> Resolution {
+    let candidates = if options.try_files.is_empty() {
+        vec![request_path.to_string()]
+    } else {
+        options
+            .try_files
+            .iter()
+            .map(|candidate| candidate.replace("{path}", request_path))
+            .collect()
+    };
+
+    let mut matched = false;
+    for candidate in candidates {
+        let Some(file_path) = sanitize_path(root, candidate.trim_start_matches('/')) else {
+            continue;
+        };
+        matched = true;
+        // Only the request path itself is canonicalized, rewritten candidates are internal
+        let is_request_path = candidate == request_path;
+        if file_path.is_dir() {
+            // Relative links of an index page resolve against the directory
+            if is_request_path && !request_path.ends_with('/') {
+                return Resolution::Redirect(true);
+            }
+            let index_path = options
+                .index
+                .iter()
+                .map(|index| file_path.join(index))
+                .find(|index_path| index_path.is_file());
+            match index_path {
+                Some(index_path) => return Resolution::File(index_path),
+                None if options.browse => return Resolution::Browse(file_path),
+                None => continue,
+            }
+        } else if file_path.is_file() {
+            if is_request_path && options.strip_trailing_slash && request_path.ends_with('/') {
+                return Resolution::Redirect(false);
+            }
+            return Resolution::File(file_path);
+        }
+    }
+
+    if matched {
+        Resolution::NotFound
+    } else {
+        Resolution::NotMatched
+    }
+}
+
+/// Serves `page` under `root` as the body of an error response with the original status
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn error_page<S>(
+    root: Option<&str>,
+    page: &str,
+    status: StatusCode,
+    request: &Request<BytesMut>,
+    socket: &mut S,
+) -> Result<StatusCode, CbltError>
+where
+    S: AsyncWriteExt + Unpin,
+{
+    let not_found = || CbltError::ResponseError {
+        details: format!("Error page not found: {}", page),
+        status_code: StatusCode::NOT_FOUND,
+    };
+    let page_path = root
+        .and_then(|root| sanitize_path(Path::new(root), page.trim_start_matches('/')))
+        .ok_or_else(not_found)?;
+    let body = fs::read(&page_path).await.map_err(|_| not_found())?;
+    let response = Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, mime_type(&page_path))
+        .body(BytesMut::from(&body[..]))?;
+    respond(socket, request, response).await?;
+    Ok(status)
+}
+
+/// Permanent redirect to the request path with its trailing slash added or stripped,
+/// the query string is kept
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn redirect<S>(
+    socket: &mut S,
+    request: &Request<BytesMut>,
+    add_slash: bool,
+) -> Result<StatusCode, CbltError>
+where
+    S: AsyncWriteExt + Unpin,
+{
+    // Leading slashes are collapsed, "//host/" would be a protocol relative URL
+    let path = request.uri().path().trim_start_matches('/');
+    let mut location = if add_slash {
+        format!("/{}/", path)
+    } else {
+        format!("/{}", path.trim_end_matches('/'))
+    };
+    if let Some(query) = request.uri().query() {
+        location.push('?');
+        location.push_str(query);
+    }
+    let response = Response::builder()
+        .status(StatusCode::MOVED_PERMANENTLY)
+        .header(LOCATION, location)
+        .body(BytesMut::new())?;
+    respond(socket, request, response).await?;
+    Ok(StatusCode::MOVED_PERMANENTLY)
+}
+
+/// Opens the sibling of `file_path` compressed with the best encoding the client accepts,
+/// e.g. `app.js.br` for `app.js`. Range requests always address the original file.
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn open_precompressed(
+    file_path: &Path,
+    request: &Request<BytesMut>,
+    encodings: &[Encoding],
+) -> Option<(File, Encoding)> {
+    if encodings.is_empty() || request.headers().contains_key(RANGE) {
+        return None;
+    }
+    for encoding in preferred_encodings(request, encodings.iter().copied()) {
+        let mut sidecar_path = file_path.as_os_str().to_owned();
+        sidecar_path.push(".");
+        sidecar_path.push(encoding.extension());
+        if let Ok(sidecar) = File::open(&sidecar_path).await {
+            if sidecar.metadata().await.is_ok_and(|m| m.is_file()) {
+                return Some((sidecar, encoding));
+            }
+        }
+    }
+    None
+}
+
+/// Guess the MIME type based on the file extension
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn mime_type(file_path: &Path) -> String {
+    mime_guess::from_path(file_path)
+        .first_or_octet_stream()
+        .to_string()
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn vary_header(headers: &mut HeaderMap, compressible: bool) {
+    if compressible {
+        headers.insert(VARY, HeaderValue::from_static("Accept-Encoding"));
+    }
+}
+
+/// Decodes `%XX` escapes of a request path, `None` for malformed escapes or non UTF-8 names
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn percent_decode(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    if decoded.contains(&0) {
+        return None;
+    }
+    String::from_utf8(decoded).ok()
+}
+
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
 fn sanitize_path(base_path: &Path, requested_path: &str) -> Option<PathBuf> {
     let mut full_path = base_path.to_path_buf();
@@ -140,3 +542,185 @@ fn sanitize_path(base_path: &Path, requested_path: &str) -> Option<PathBuf> {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::config::FileServerOptions;
+    use crate::file_server::{
+        evaluate_preconditions, if_range_matches, percent_decode, resolve_path, Precondition,
+        Resolution, Validators,
+    };
+    use bytes::BytesMut;
+    use http::{Method, Request};
+    use std::error::Error;
+    use std::fs;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn validators() -> Validators {
+        Validators {
+            etag: "\"5f5e100-2a\"".to_string(),
+            last_modified: Some(UNIX_EPOCH + Duration::from_secs(100_000_000)),
+        }
+    }
+
+    fn request(method: Method, headers: &[(&str, &str)]) -> Request<BytesMut> {
+        let mut builder = Request::builder().method(method).uri("/index.html");
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(BytesMut::new()).unwrap()
+    }
+
+    #[test]
+    fn test_preconditions() {
+        let validators = validators();
+        let check = |method: Method, headers: &[(&str, &str)]| {
+            evaluate_preconditions(&request(method, headers), &validators)
+        };
+        // 1973-03-03 09:46:40 GMT is the modification time
+        let modified = "Sat, 03 Mar 1973 09:46:40 GMT";
+        let earlier = "Sat, 03 Mar 1973 09:46:39 GMT";
+
+        assert_eq!(check(Method::GET, &[]), Precondition::Proceed);
+        assert_eq!(
+            check(
+                Method::GET,
+                &[("If-None-Match", "\"other\", W/\"5f5e100-2a\"")]
+            ),
+            Precondition::NotModified
+        );
+        assert_eq!(
+            check(Method::PUT, &[("If-None-Match", "*")]),
+            Precondition::Failed
+        );
+        assert_eq!(
+            check(Method::GET, &[("If-Modified-Since", modified)]),
+            Precondition::NotModified
+        );
+        assert_eq!(
+            check(Method::GET, &[("If-Modified-Since", earlier)]),
+            Precondition::Proceed
+        );
+        // If-None-Match takes precedence over If-Modified-Since
+        assert_eq!(
+            check(
+                Method::GET,
+                &[
+                    ("If-None-Match", "\"other\""),
+                    ("If-Modified-Since", modified)
+                ]
+            ),
+            Precondition::Proceed
+        );
+        assert_eq!(
+            check(Method::GET, &[("If-Match", "\"other\"")]),
+            Precondition::Failed
+        );
+        assert_eq!(
+            check(Method::GET, &[("If-Match", "W/\"5f5e100-2a\"")]),
+            Precondition::Failed
+        );
+        assert_eq!(
+            check(Method::GET, &[("If-Match", "\"5f5e100-2a\"")]),
+            Precondition::Proceed
+        );
+        assert_eq!(
+            check(Method::GET, &[("If-Unmodified-Since", earlier)]),
+            Precondition::Failed
+        );
+        assert_eq!(
+            check(Method::GET, &[("If-Unmodified-Since", "not a date")]),
+            Precondition::Proceed
+        );
+
+        let range = |if_range: &str| {
+            if_range_matches(
+                &request(Method::GET, &[("If-Range", if_range)]),
+                &validators,
+            )
+        };
+        assert!(range("\"5f5e100-2a\""));
+        assert!(!range("W/\"5f5e100-2a\""));
+        assert!(range(modified));
+        assert!(!range(earlier));
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("/y%20z.txt").as_deref(), Some("/y z.txt"));
+        assert_eq!(percent_decode("/%D0%B0").as_deref(), Some("/а"));
+        assert_eq!(percent_decode("/plain").as_deref(), Some("/plain"));
+        assert_eq!(percent_decode("/bad%2"), None);
+        assert_eq!(percent_decode("/bad%zz"), None);
+        assert_eq!(percent_decode("/nul%00"), None);
+    }
+
+    #[test]
+    fn test_resolve_path() -> Result<(), Box<dyn Error>> {
+        let root = std::env::temp_dir().join(format!("cblt-resolve-{}", std::process::id()));
+        fs::create_dir_all(root.join("docs/guide"))?;
+        fs::create_dir_all(root.join("empty"))?;
+        fs::write(root.join("about.html"), "about")?;
+        fs::write(root.join("docs/index.htm"), "docs")?;
+        fs::write(root.join("docs/guide/index.html"), "guide")?;
+        fs::write(root.join("404.html"), "missing")?;
+
+        let mut options = FileServerOptions {
+            index: vec!["index.html".to_string(), "index.htm".to_string()],
+            ..FileServerOptions::default()
+        };
+        assert_eq!(
+            resolve_path(&root, "/docs/", &options),
+            Resolution::File(root.join("docs/index.htm"))
+        );
+        assert_eq!(
+            resolve_path(&root, "/docs", &options),
+            Resolution::Redirect(true)
+        );
+        assert_eq!(
+            resolve_path(&root, "/about.html/", &options),
+            Resolution::File(root.join("about.html"))
+        );
+        assert_eq!(
+            resolve_path(&root, "/docs/guide/", &options),
+            Resolution::File(root.join("docs/guide/index.html"))
+        );
+        assert_eq!(
+            resolve_path(&root, "/about", &options),
+            Resolution::NotFound
+        );
+        assert_eq!(
+            resolve_path(&root, "/../etc", &options),
+            Resolution::NotMatched
+        );
+
+        options.try_files = vec![
+            "{path}".to_string(),
+            "{path}.html".to_string(),
+            "/404.html".to_string(),
+        ];
+        assert_eq!(
+            resolve_path(&root, "/about", &options),
+            Resolution::File(root.join("about.html"))
+        );
+        assert_eq!(
+            resolve_path(&root, "/empty/", &options),
+            Resolution::File(root.join("404.html"))
+        );
+
+        options.browse = true;
+        assert_eq!(
+            resolve_path(&root, "/empty/", &options),
+            Resolution::Browse(root.join("empty"))
+        );
+
+        options.strip_trailing_slash = true;
+        assert_eq!(
+            resolve_path(&root, "/about.html/", &options),
+            Resolution::Redirect(false)
+        );
+
+        fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+}
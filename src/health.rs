@@ -0,0 +1,278 @@
+use crate::config::{HealthCheckOptions, PassiveHealthOptions};
+use crate::error::CbltError;
+use crate::reverse_proxy::{current_timestamp_seconds, AliveState};
+use crate::upstream::{BackendStream, UpstreamTls};
+use bytes::BytesMut;
+use futures_util::future::join_all;
+use http::header::TRANSFER_ENCODING;
+use http::StatusCode;
+#[cfg(debug_assertions)]
+use log::debug;
+use log::info;
+use std::collections::VecDeque;
+use std::sync::{Mutex, Weak};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::RwLock;
+use tokio::time::{interval, timeout, Instant, MissedTickBehavior};
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+/// Largest probe response that is read, the rest of a longer body is ignored
+const MAX_PROBE_RESPONSE: usize = 64 * 1024;
+
+/// Probes the backends every interval and marks them healthy or unhealthy once the
+/// thresholds are reached. Ends when the state of the backends is dropped on reload.
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn health_checks(
+    backends: Vec<(String, Weak<RwLock<AliveState>>)>,
+    options: HealthCheckOptions,
+    upstream_tls: Option<UpstreamTls>,
+) {
+    let mut counters = vec![(0u32, 0u32); backends.len()]; // consecutive (passes, fails)
+    let mut ticks = interval(options.interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        if backends.iter().all(|(_, state)| state.strong_count() == 0) {
+            return;
+        }
+
+        let probes = backends
+            .iter()
+            .map(|(url, _)| probe(url, &options, upstream_tls.as_ref()));
+        let results = join_all(probes).await;
+
+        for (((url, state), result), (passes, fails)) in
+            backends.iter().zip(results).zip(counters.iter_mut())
+        {
+            let Some(state) = state.upgrade() else {
+                continue;
+            };
+            let mut alive_state = state.write().await;
+            match result {
+                Ok(()) => {
+                    *passes += 1;
+                    *fails = 0;
+                    if *passes >= options.passes && !matches!(*alive_state, AliveState::Alive(_)) {
+                        info!("Backend healthy: {}", url);
+                        *alive_state = AliveState::Alive(current_timestamp_seconds());
+                    }
+                }
+                Err(_err) => {
+                    #[cfg(debug_assertions)]
+                    debug!("Health check of {} failed: {}", url, _err);
+                    *fails += 1;
+                    *passes = 0;
+                    if *fails >= options.fails && !matches!(*alive_state, AliveState::Unhealthy) {
+                        info!("Backend unhealthy: {}", url);
+                        *alive_state = AliveState::Unhealthy;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Requests the health URI from a backend and checks the status and the body
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn probe(
+    url: &str,
+    options: &HealthCheckOptions,
+    upstream_tls: Option<&UpstreamTls>,
+) -> Result<(), CbltError> {
+    let response = timeout(options.timeout, request(url, options, upstream_tls))
+        .await
+        .map_err(|_| unhealthy("Health check timed out".to_string()))??;
+    check_response(&response, options)
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn request(
+    url: &str,
+    options: &HealthCheckOptions,
+    upstream_tls: Option<&UpstreamTls>,
+) -> Result<BytesMut, CbltError> {
+    let uri = format!("{}{}", url, options.uri)
+        .parse::<http::Uri>()
+        .map_err(|e| unhealthy(e.to_string()))?;
+    let host = uri
+        .host()
+        .ok_or_else(|| unhealthy(format!("Invalid backend address: {}", url)))?;
+    let https = uri.scheme_str() == Some("https");
+    let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+    let host_port = format!("{}:{}", host, port);
+
+    let stream = TcpStream::connect(host_port.as_str()).await?;
+    let mut stream = match upstream_tls {
+        Some(upstream_tls) if https => upstream_tls.connect(host, stream).await?,
+        _ => BackendStream::Plain(stream),
+    };
+    let path = uri.path_and_query().map_or("/", |pq| pq.as_str());
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: cblt-health-check\r\nConnection: close\r\n\r\n",
+        path, host_port
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = BytesMut::with_capacity(4096);
+    while response.len() < MAX_PROBE_RESPONSE && stream.read_buf(&mut response).await? > 0 {}
+    Ok(response)
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn check_response(response: &[u8], options: &HealthCheckOptions) -> Result<(), CbltError> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut res = httparse::Response::new(&mut headers);
+    let header_len = match res.parse(response) {
+        Ok(httparse::Status::Complete(header_len)) => header_len,
+        _ => return Err(unhealthy("Invalid health check response".to_string())),
+    };
+    let status = res.code.unwrap_or(0);
+    if !(options.status.0..=options.status.1).contains(&status) {
+        return Err(unhealthy(format!("Unexpected status {}", status)));
+    }
+
+    if let Some(regex) = &options.body {
+        let chunked = res.headers.iter().any(|header| {
+            header.name.eq_ignore_ascii_case(TRANSFER_ENCODING.as_str())
+                && String::from_utf8_lossy(header.value)
+                    .to_ascii_lowercase()
+                    .contains("chunked")
+        });
+        let body = if chunked {
+            dechunk(&response[header_len..])
+        } else {
+            response[header_len..].to_vec()
+        };
+        if !regex.is_match(&String::from_utf8_lossy(&body)) {
+            return Err(unhealthy("Unexpected body".to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// Data of a chunked body, as far as it was read
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn dechunk(mut chunked: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(chunked.len());
+    while let Ok(httparse::Status::Complete((size_len, chunk_size))) =
+        httparse::parse_chunk_size(chunked)
+    {
+        chunked = &chunked[size_len..];
+        let chunk_size = usize::try_from(chunk_size).unwrap_or(usize::MAX);
+        if chunk_size == 0 || chunk_size > chunked.len() {
+            body.extend_from_slice(&chunked[..chunk_size.min(chunked.len())]);
+            break;
+        }
+        body.extend_from_slice(&chunked[..chunk_size]);
+        // Chunk data is followed by CRLF
+        chunked = chunked.get(chunk_size + 2..).unwrap_or_default();
+    }
+    body
+}
+
+/// Recent failures of proxied requests to one backend, for the passive health checks
+#[derive(Debug, Default)]
+pub struct FailureWindow {
+    failures: Mutex<VecDeque<Instant>>,
+}
+
+impl FailureWindow {
+    /// Records a failure and tells whether `max_fails` of them fall within the window.
+    /// The window starts over then, so a revived backend gets a fresh count.
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn record(&self, now: Instant, options: &PassiveHealthOptions) -> bool {
+        let Ok(mut failures) = self.failures.lock() else {
+            return false;
+        };
+        while failures
+            .front()
+            .is_some_and(|failure| now.duration_since(*failure) >= options.window)
+        {
+            failures.pop_front();
+        }
+        failures.push_back(now);
+        if failures.len() >= options.max_fails as usize {
+            failures.clear();
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn unhealthy(details: String) -> CbltError {
+    CbltError::ResponseError {
+        details,
+        status_code: StatusCode::BAD_GATEWAY,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{HealthCheckOptions, PassiveHealthOptions};
+    use crate::health::{check_response, dechunk, FailureWindow};
+    use regex::Regex;
+    use std::error::Error;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    #[test]
+    fn test_check_response() -> Result<(), Box<dyn Error>> {
+        let options = HealthCheckOptions {
+            uri: "/health".to_string(),
+            body: Some(Regex::new("^status: (ok|degraded)$")?),
+            ..HealthCheckOptions::default()
+        };
+        assert!(check_response(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nstatus: ok",
+            &options
+        )
+        .is_ok());
+        assert!(check_response(b"HTTP/1.1 200 OK\r\n\r\nstatus: down", &options).is_err());
+        assert!(check_response(b"HTTP/1.1 503 Unavailable\r\n\r\nstatus: ok", &options).is_err());
+        assert!(check_response(b"HTTP/1.1 200 OK\r\nContent-Le", &options).is_err());
+        assert!(check_response(
+            b"HTTP/1.1 204 No Content\r\n\r\n",
+            &HealthCheckOptions::default()
+        )
+        .is_ok());
+
+        let chunked = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                        7\r\nstatus:\r\n3\r\n ok\r\n0\r\n\r\n";
+        assert!(check_response(chunked, &options).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_dechunk() {
+        assert_eq!(
+            dechunk(b"4\r\nWiki\r\n5;x=1\r\npedia\r\n0\r\n\r\n"),
+            b"Wikipedia"
+        );
+        assert_eq!(dechunk(b"4\r\nWiki\r\n9\r\nped"), b"Wikiped");
+        assert_eq!(dechunk(b"zz"), b"");
+    }
+
+    #[test]
+    fn test_failure_window() {
+        let options = PassiveHealthOptions {
+            window: Duration::from_secs(10),
+            max_fails: 3,
+            ..PassiveHealthOptions::default()
+        };
+        let window = FailureWindow::default();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        assert!(!window.record(at(0), &options));
+        assert!(!window.record(at(5), &options));
+        // The first failure is out of the window
+        assert!(!window.record(at(12), &options));
+        assert!(window.record(at(14), &options));
+        // Counting starts over
+        assert!(!window.record(at(15), &options));
+        assert!(!window.record(at(16), &options));
+        assert!(window.record(at(17), &options));
+    }
+}
@@ -0,0 +1,346 @@
+use crate::directive::{host_limits, request_process};
+use crate::error::CbltError;
+use crate::request::{check_request_limits, BodyFraming, BUF_SIZE};
+use crate::response::error_response;
+use crate::server::{ConnectionOptions, ServerSettings};
+use bytes::{Bytes, BytesMut};
+use futures_util::future::poll_fn;
+use h2::server::SendResponse;
+use h2::{RecvStream, SendStream};
+use http::header::{CONTENT_LENGTH, HOST};
+use http::{HeaderName, HeaderValue, Method, Request, Response, StatusCode};
+use httparse::Status;
+#[cfg(debug_assertions)]
+use log::error;
+use log::info;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::time::{sleep, timeout, timeout_at, Instant};
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+pub const H2_MAX_CONCURRENT_STREAMS: u32 = 100;
+
+/// Headers that only make sense for a single HTTP/1.1 connection and are illegal in HTTP/2
+const CONNECTION_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn h2_process<S>(
+    socket: S,
+    settings: Arc<ServerSettings>,
+    addr: SocketAddr,
+    options: Arc<ConnectionOptions>,
+) -> Result<(), CbltError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let max_header_list_size = u32::try_from(options.limits.max_header_bytes).unwrap_or(u32::MAX);
+    let handshake = h2::server::Builder::new()
+        .max_concurrent_streams(H2_MAX_CONCURRENT_STREAMS)
+        .max_header_list_size(max_header_list_size)
+        .handshake::<_, Bytes>(socket);
+    let mut connection = match timeout(options.header_timeout, handshake).await {
+        Ok(connection) => connection?,
+        Err(_) => {
+            info!("Header timeout: {}", addr);
+            return Ok(());
+        }
+    };
+
+    // The connection is idle only while no stream is in flight
+    let active_streams = Arc::new(AtomicUsize::new(0));
+    let mut closing = false;
+    loop {
+        let idle = !closing && active_streams.load(Ordering::SeqCst) == 0;
+        let result = tokio::select! {
+            result = connection.accept() => result,
+            _ = sleep(options.keep_alive_timeout), if idle => {
+                info!("Idle timeout: {}", addr);
+                connection.graceful_shutdown();
+                closing = true;
+                continue;
+            }
+        };
+        let Some(result) = result else {
+            break;
+        };
+        let (request, respond) = result?;
+        let settings = settings.clone();
+        let options = options.clone();
+        let active_streams = active_streams.clone();
+        active_streams.fetch_add(1, Ordering::SeqCst);
+        tokio::spawn(async move {
+            if let Err(err) = stream_process(request, respond, settings, addr, &options).await {
+                #[cfg(debug_assertions)]
+                error!("Error: {}", err);
+            }
+            active_streams.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+    Ok(())
+}
+
+/// Runs one HTTP/2 stream through the HTTP/1.1 directive pipeline over an in-memory pipe
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn stream_process(
+    request: Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    settings: Arc<ServerSettings>,
+    addr: SocketAddr,
+    options: &ConnectionOptions,
+) -> Result<(), CbltError> {
+    let (parts, mut recv) = request.into_parts();
+    let mut request = Request::from_parts(parts, BytesMut::new());
+
+    // Host lookup works on the Host header, HTTP/2 carries it in :authority
+    if !request.headers().contains_key(HOST) {
+        if let Some(authority) = request.uri().authority() {
+            let host = HeaderValue::from_str(authority.as_str())?;
+            request.headers_mut().insert(HOST, host);
+        }
+    }
+
+    let limits = host_limits(&settings, &request, &options.limits);
+    let framing = match request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<usize>().ok())
+    {
+        Some(content_length) => BodyFraming::ContentLength(content_length),
+        None => BodyFraming::Empty,
+    };
+    if let Err(err) = check_request_limits(&request, framing, &limits) {
+        return reject(&mut respond, err);
+    }
+
+    let mut body = BytesMut::new();
+    let deadline = Instant::now() + options.body_timeout;
+    loop {
+        let chunk = match timeout_at(deadline, recv.data()).await {
+            Ok(Some(chunk)) => chunk?,
+            Ok(None) => break,
+            Err(_) => {
+                info!("Body timeout: {}", addr);
+                return reject(
+                    &mut respond,
+                    CbltError::RequestError {
+                        details: "Body timeout".to_string(),
+                        status_code: StatusCode::REQUEST_TIMEOUT,
+                    },
+                );
+            }
+        };
+        let _ = recv.flow_control().release_capacity(chunk.len());
+        if body.len() + chunk.len() > limits.max_body_size {
+            return reject(
+                &mut respond,
+                CbltError::RequestError {
+                    details: "Payload too large".to_string(),
+                    status_code: StatusCode::PAYLOAD_TOO_LARGE,
+                },
+            );
+        }
+        body.extend_from_slice(&chunk);
+    }
+    // HTTP/2 bodies may come without Content-Length, the HTTP/1.1 pipeline needs it
+    if !body.is_empty() {
+        request
+            .headers_mut()
+            .insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+    }
+    *request.body_mut() = body;
+
+    let (mut local, mut remote) = tokio::io::duplex(BUF_SIZE);
+    // The whole request body is already read, so handlers see EOF on the client side
+    local.shutdown().await?;
+
+    let is_head = request.method() == Method::HEAD;
+    let handler = async {
+        let result = request_process(&mut remote, &settings, addr, &mut request).await;
+        remote.shutdown().await?;
+        result
+    };
+    let forward = forward_response(&mut local, &mut respond, is_head);
+    let (handler_result, forward_result) = tokio::join!(handler, forward);
+
+    if let Err(err) = forward_result {
+        // Nothing usable was written by the handler
+        if let Ok(response) = Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(())
+        {
+            let _ = respond.send_response(response, true);
+        }
+        return Err(err);
+    }
+    handler_result
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn reject(respond: &mut SendResponse<Bytes>, err: CbltError) -> Result<(), CbltError> {
+    let status_code = match &err {
+        CbltError::RequestError { status_code, .. }
+        | CbltError::ResponseError { status_code, .. } => *status_code,
+        _ => StatusCode::BAD_REQUEST,
+    };
+    let (parts, body) = error_response(status_code)?.into_parts();
+    let response = Response::from_parts(parts, ());
+    let mut send = respond.send_response(response, false)?;
+    send.send_data(body.freeze(), true)?;
+    Err(err)
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn forward_response(
+    local: &mut DuplexStream,
+    respond: &mut SendResponse<Bytes>,
+    is_head: bool,
+) -> Result<(), CbltError> {
+    let mut buf = BytesMut::with_capacity(BUF_SIZE);
+
+    let (response, header_len, content_length, chunked) = loop {
+        if local.read_buf(&mut buf).await? == 0 {
+            return Err(CbltError::ResponseError {
+                details: "Empty response".to_string(),
+                status_code: StatusCode::INTERNAL_SERVER_ERROR,
+            });
+        }
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut res = httparse::Response::new(&mut headers);
+        match res.parse(&buf) {
+            Ok(Status::Complete(header_len)) => {
+                let status = StatusCode::from_u16(res.code.unwrap_or(500))
+                    .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+                let mut builder = Response::builder().status(status);
+                let mut content_length = None;
+                let mut chunked = false;
+                for header in res.headers.iter() {
+                    if header.name.eq_ignore_ascii_case("Transfer-Encoding") {
+                        chunked = std::str::from_utf8(header.value)
+                            .map(|v| v.to_ascii_lowercase().contains("chunked"))
+                            .unwrap_or(false);
+                    }
+                    if header.name.eq_ignore_ascii_case("Content-Length") {
+                        content_length = std::str::from_utf8(header.value)
+                            .ok()
+                            .and_then(|v| v.trim().parse::<usize>().ok());
+                    }
+                    if CONNECTION_HEADERS
+                        .iter()
+                        .any(|h| header.name.eq_ignore_ascii_case(h))
+                    {
+                        continue;
+                    }
+                    let name = HeaderName::from_bytes(header.name.as_bytes())?;
+                    let value = HeaderValue::from_bytes(header.value)?;
+                    builder = builder.header(name, value);
+                }
+                break (builder.body(())?, header_len, content_length, chunked);
+            }
+            Ok(Status::Partial) => continue,
+            Err(err) => {
+                return Err(CbltError::ResponseError {
+                    details: err.to_string(),
+                    status_code: StatusCode::INTERNAL_SERVER_ERROR,
+                });
+            }
+        }
+    };
+    let _ = buf.split_to(header_len);
+
+    // HEAD responses describe a body without carrying one
+    if is_head || content_length == Some(0) {
+        respond.send_response(response, true)?;
+        return Ok(());
+    }
+    let mut send = respond.send_response(response, false)?;
+
+    if chunked {
+        loop {
+            let (size_len, chunk_size) = loop {
+                match httparse::parse_chunk_size(&buf) {
+                    Ok(Status::Complete(parsed)) => break parsed,
+                    Ok(Status::Partial) => read_more(local, &mut buf).await?,
+                    Err(_) => {
+                        return Err(CbltError::ResponseError {
+                            details: "Invalid chunk size".to_string(),
+                            status_code: StatusCode::BAD_GATEWAY,
+                        });
+                    }
+                }
+            };
+            let _ = buf.split_to(size_len);
+            if chunk_size == 0 {
+                break;
+            }
+            let mut remaining = chunk_size as usize;
+            while remaining > 0 {
+                if buf.is_empty() {
+                    read_more(local, &mut buf).await?;
+                }
+                let data = buf.split_to(remaining.min(buf.len()));
+                remaining -= data.len();
+                send_data(&mut send, data.freeze()).await?;
+            }
+            while buf.len() < 2 {
+                read_more(local, &mut buf).await?;
+            }
+            let _ = buf.split_to(2);
+        }
+    } else {
+        let mut remaining = content_length.unwrap_or(usize::MAX);
+        loop {
+            if !buf.is_empty() {
+                let data = buf.split_to(remaining.min(buf.len()));
+                remaining -= data.len();
+                send_data(&mut send, data.freeze()).await?;
+            }
+            if remaining == 0 || local.read_buf(&mut buf).await? == 0 {
+                break;
+            }
+        }
+    }
+
+    send.send_data(Bytes::new(), true)?;
+    Ok(())
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn send_data(send: &mut SendStream<Bytes>, mut data: Bytes) -> Result<(), CbltError> {
+    // Respect the peer's flow control window instead of buffering the whole body
+    while !data.is_empty() {
+        send.reserve_capacity(data.len());
+        let capacity = match poll_fn(|cx| send.poll_capacity(cx)).await {
+            Some(capacity) => capacity?,
+            None => {
+                return Err(CbltError::ResponseError {
+                    details: "Stream closed".to_string(),
+                    status_code: StatusCode::INTERNAL_SERVER_ERROR,
+                });
+            }
+        };
+        let chunk = data.split_to(capacity.min(data.len()));
+        send.send_data(chunk, false)?;
+    }
+    Ok(())
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn read_more(local: &mut DuplexStream, buf: &mut BytesMut) -> Result<(), CbltError> {
+    if local.read_buf(buf).await? == 0 {
+        return Err(CbltError::ResponseError {
+            details: "Unexpected end of response".to_string(),
+            status_code: StatusCode::BAD_GATEWAY,
+        });
+    }
+    Ok(())
+}
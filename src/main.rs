@@ -1,6 +1,8 @@
-use crate::config::{load_servers_from_config, load_servers_from_docker, Directive};
+use crate::config::{
+    load_servers_from_config, load_servers_from_docker, parse_size, Directive, RequestLimits,
+};
 use crate::error::CbltError;
-use crate::server::{Server, ServerWorker};
+use crate::server::{ConnectionOptions, Server, ServerWorker};
 use clap::{Parser, ValueEnum};
 use log::{debug, error, info};
 use std::collections::hash_map::Entry;
@@ -14,14 +16,20 @@ use tracing::instrument;
 use tracing::Level;
 use tracing_subscriber::fmt::format::FmtSpan;
 use tracing_subscriber::FmtSubscriber;
+mod browse;
+mod compression;
 mod config;
 mod directive;
 mod error;
 mod file_server;
+mod health;
+mod http2;
 mod request;
 mod response;
 mod reverse_proxy;
 mod server;
+mod timeout;
+mod upstream;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -34,6 +42,42 @@ struct Args {
     #[arg(long, default_value_t = 10000)]
     max_connections: usize,
 
+    /// Idle time before a keep-alive connection is closed
+    #[arg(long, default_value = "75s")]
+    keep_alive_timeout: humantime::Duration,
+
+    /// Time allowed to receive the request headers
+    #[arg(long, default_value = "30s")]
+    header_timeout: humantime::Duration,
+
+    /// Time allowed to receive the request body
+    #[arg(long, default_value = "60s")]
+    body_timeout: humantime::Duration,
+
+    /// Time a response write may stall before the connection is closed
+    #[arg(long, default_value = "30s")]
+    write_timeout: humantime::Duration,
+
+    /// Maximum number of requests served over one keep-alive connection
+    #[arg(long, default_value_t = 1000)]
+    max_requests: usize,
+
+    /// Maximum number of request headers
+    #[arg(long, default_value_t = 100)]
+    max_header_count: usize,
+
+    /// Maximum size of the request headers
+    #[arg(long, default_value = "16KB", value_parser = parse_size)]
+    max_header_bytes: usize,
+
+    /// Maximum length of the request URI
+    #[arg(long, default_value = "8KB", value_parser = parse_size)]
+    max_uri_length: usize,
+
+    /// Maximum size of the request body
+    #[arg(long, default_value = "10MB", value_parser = parse_size)]
+    max_body_size: usize,
+
     /// Enable reload feature
     #[arg(long)]
     reload: bool,
@@ -192,10 +236,28 @@ impl ServerSupervisor {
 
         for (port, server) in servers {
             if let Some(worker) = self.workers.get_mut(&port) {
-                worker.update(server.hosts, server.cert, server.key).await?;
+                worker
+                    .update(server.hosts, server.cert, server.key, server.protocols)
+                    .await?;
                 info!("Server worker updated on port: {}", port);
             } else if let Ok(server_worker) = ServerWorker::new(server.clone()).await {
-                if let Err(err) = server_worker.run(args.max_connections).await {
+                let connection_options = ConnectionOptions {
+                    keep_alive_timeout: args.keep_alive_timeout.into(),
+                    header_timeout: args.header_timeout.into(),
+                    body_timeout: args.body_timeout.into(),
+                    write_timeout: args.write_timeout.into(),
+                    max_requests: args.max_requests,
+                    limits: RequestLimits {
+                        max_header_count: args.max_header_count,
+                        max_header_bytes: args.max_header_bytes,
+                        max_uri_length: args.max_uri_length,
+                        max_body_size: args.max_body_size,
+                    },
+                };
+                if let Err(err) = server_worker
+                    .run(args.max_connections, connection_options)
+                    .await
+                {
                     error!("Error: {}", err);
                 }
                 self.workers.insert(port, server_worker);
@@ -218,12 +280,17 @@ fn build_servers(
         let mut port = 80;
         let mut cert_path = None;
         let mut key_path = None;
-        directives.iter().for_each(|d| {
-            if let Directive::TlS { cert, key } = d {
+        let mut protocols_opt = None;
+        directives.iter().for_each(|d| match d {
+            Directive::TlS { cert, key } => {
                 port = 443;
                 cert_path = Some(cert.to_string());
                 key_path = Some(key.to_string());
             }
+            Directive::Protocols { protocols } => {
+                protocols_opt = Some(protocols.clone());
+            }
+            _ => {}
         });
         let parsed_host = ParsedHost::from_str(&host);
         let port = parsed_host.port.unwrap_or(port);
@@ -239,6 +306,9 @@ fn build_servers(
                 hosts.insert(host, directives);
                 server.get_mut().cert = cert_path.clone();
                 server.get_mut().key = key_path.clone();
+                if let Some(protocols) = protocols_opt {
+                    server.get_mut().protocols = protocols;
+                }
             }
             Entry::Vacant(new_server) => {
                 let mut hosts = HashMap::new();
@@ -250,6 +320,8 @@ fn build_servers(
                     hosts,
                     cert: cert_path.clone(),
                     key: key_path.clone(),
+                    protocols: protocols_opt
+                        .unwrap_or_else(|| vec!["h1".to_string(), "h2".to_string()]),
                 });
             }
         }
@@ -1,7 +1,9 @@
+use crate::config::RequestLimits;
 use crate::error::CbltError;
 use bytes::BytesMut;
+use http::header::{CONNECTION, CONTENT_LENGTH};
 use http::Version;
-use http::{Request, StatusCode};
+use http::{HeaderName, HeaderValue, Request, StatusCode};
 use httparse::Status;
 use log::error;
 use std::str;
@@ -10,50 +12,77 @@ use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tracing::instrument;
 
 pub const BUF_SIZE: usize = 8192;
-pub const HEADER_BUF_SIZE: usize = 32;
+
+/// How the body following a request head is framed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyFraming {
+    Empty,
+    ContentLength(usize),
+    Chunked,
+}
+
+/// Reads the next request head, the body is left in the buffer for `read_request_body`
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
 pub async fn socket_to_request<S>(
     socket: &mut S,
     mut buf: &mut BytesMut,
-) -> Result<Request<BytesMut>, CbltError>
+    limits: &RequestLimits,
+) -> Result<Option<(Request<BytesMut>, BodyFraming)>, CbltError>
 where
     S: AsyncReadExt + AsyncWriteExt + Unpin,
 {
     loop {
-        let bytes_read = socket.read_buf(&mut buf).await.unwrap_or(0);
-        if bytes_read == 0 {
-            break;
-        }
-        // Try to parse the headers
-        let mut headers = [httparse::EMPTY_HEADER; HEADER_BUF_SIZE];
-        let mut req = httparse::Request::new(&mut headers);
+        // Pipelined requests may already be in the buffer
+        if !buf.is_empty() {
+            if uri_length(buf) > limits.max_uri_length {
+                return Err(CbltError::RequestError {
+                    details: "URI too long".to_string(),
+                    status_code: StatusCode::URI_TOO_LONG,
+                });
+            }
+
+            // Try to parse the headers
+            let mut headers = vec![httparse::EMPTY_HEADER; limits.max_header_count];
+            let mut req = httparse::Request::new(&mut headers);
 
-        match req.parse(buf) {
-            Ok(Status::Complete(header_len)) => {
-                let (request, _) = match parse_request_headers(header_len, buf, socket).await? {
-                    Some((req, content_length)) => (req, content_length),
-                    None => {
-                        return Err(CbltError::RequestError {
+            match req.parse(buf) {
+                Ok(Status::Complete(header_len)) => {
+                    if header_len > limits.max_header_bytes {
+                        return Err(headers_too_large());
+                    }
+                    return match parse_request_headers(header_len, buf, limits.max_header_count)? {
+                        Some(head) => Ok(Some(head)),
+                        None => Err(CbltError::RequestError {
                             details: "Bad request".to_string(),
                             status_code: StatusCode::BAD_REQUEST,
-                        });
+                        }),
+                    };
+                }
+                Ok(Status::Partial) => {
+                    // Need to read more data
+                    if buf.len() > limits.max_header_bytes {
+                        return Err(headers_too_large());
                     }
-                };
-
-                // #[cfg(debug_assertions)]
-                // debug!("{:?}", request);
-                return Ok(request);
-            }
-            Ok(Status::Partial) => {
-                // Need to read more data
-                continue;
+                }
+                Err(httparse::Error::TooManyHeaders) => {
+                    return Err(headers_too_large());
+                }
+                Err(err) => {
+                    return Err(CbltError::RequestError {
+                        details: err.to_string(),
+                        status_code: StatusCode::BAD_REQUEST,
+                    });
+                }
             }
-            Err(err) => {
-                return Err(CbltError::RequestError {
-                    details: err.to_string(),
-                    status_code: StatusCode::BAD_REQUEST,
-                });
+        }
+
+        let bytes_read = socket.read_buf(&mut buf).await.unwrap_or(0);
+        if bytes_read == 0 {
+            if buf.is_empty() {
+                // Connection closed by the client between requests
+                return Ok(None);
             }
+            break;
         }
     }
 
@@ -64,15 +93,13 @@ where
 }
 
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
-pub async fn parse_request_headers<S>(
+pub fn parse_request_headers(
     header_len: usize,
     buf: &mut BytesMut,
-    socket: &mut S,
-) -> Result<Option<(Request<BytesMut>, Option<usize>)>, CbltError>
-where
-    S: AsyncReadExt + AsyncWriteExt + Unpin,
-{
-    let req_str = match str::from_utf8(&buf[..header_len]) {
+    max_header_count: usize,
+) -> Result<Option<(Request<BytesMut>, BodyFraming)>, CbltError> {
+    let head = buf.split_to(header_len);
+    let req_str = match str::from_utf8(&head) {
         Ok(v) => v,
         Err(err) => {
             return Err(CbltError::RequestError {
@@ -81,7 +108,7 @@ where
             });
         }
     };
-    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut headers = vec![httparse::EMPTY_HEADER; max_header_count];
     let mut req = httparse::Request::new(&mut headers);
 
     match req.parse(req_str.as_bytes()) {
@@ -106,38 +133,48 @@ where
             let mut builder = Request::builder().method(method).uri(path).version(version);
 
             let mut content_length_opt = None;
+            let mut transfer_encoding_opt = None;
 
             for header in req.headers.iter() {
                 let name = header.name;
                 let value = header.value;
-                builder = builder.header(name, value);
 
+                // Framing headers are set again once the body has been read
                 if name.eq_ignore_ascii_case("Content-Length") {
                     if let Ok(s) = std::str::from_utf8(value) {
                         if let Ok(len) = s.trim().parse::<usize>() {
                             content_length_opt = Some(len);
                         }
                     }
+                    continue;
+                } else if name.eq_ignore_ascii_case("Transfer-Encoding") {
+                    transfer_encoding_opt = std::str::from_utf8(value).ok();
+                    continue;
                 }
+                builder = builder.header(name, value);
             }
 
-            if let Some(content_length) = content_length_opt {
-                let mut body = buf.split_off(header_len);
-
-                while body.len() < content_length {
-                    let bytes_read = socket.read_buf(&mut body).await.unwrap_or(0);
-                    if bytes_read == 0 {
-                        break;
-                    }
+            let framing = if let Some(transfer_encoding) = transfer_encoding_opt {
+                // Transfer-Encoding overrides Content-Length, and chunked must be the final coding
+                let chunked = transfer_encoding
+                    .rsplit(',')
+                    .next()
+                    .map(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+                    .unwrap_or(false);
+                if !chunked {
+                    return Err(CbltError::RequestError {
+                        details: format!("Unsupported transfer encoding: {}", transfer_encoding),
+                        status_code: StatusCode::BAD_REQUEST,
+                    });
                 }
-
-                Ok(builder.body(body).ok().map(|req| (req, content_length_opt)))
+                BodyFraming::Chunked
+            } else if let Some(content_length) = content_length_opt {
+                BodyFraming::ContentLength(content_length)
             } else {
-                Ok(builder
-                    .body(BytesMut::new())
-                    .ok()
-                    .map(|req| (req, content_length_opt)))
-            }
+                BodyFraming::Empty
+            };
+
+            Ok(builder.body(BytesMut::new()).ok().map(|req| (req, framing)))
         }
         Ok(Status::Partial) => Ok(None),
         Err(err) => {
@@ -148,39 +185,471 @@ where
     }
 }
 
+/// Reads the body announced by the request head into the request
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn read_request_body<S>(
+    socket: &mut S,
+    buf: &mut BytesMut,
+    request: &mut Request<BytesMut>,
+    framing: BodyFraming,
+    limits: &RequestLimits,
+) -> Result<(), CbltError>
+where
+    S: AsyncReadExt + Unpin,
+{
+    let body = match framing {
+        BodyFraming::Empty => return Ok(()),
+        BodyFraming::ContentLength(content_length) => {
+            if content_length > limits.max_body_size {
+                return Err(payload_too_large());
+            }
+            while buf.len() < content_length {
+                read_more(socket, buf).await?;
+            }
+            buf.split_to(content_length)
+        }
+        BodyFraming::Chunked => {
+            let (body, trailers) = read_chunked_body(socket, buf, limits).await?;
+            for (name, value) in trailers {
+                request.headers_mut().append(
+                    HeaderName::from_bytes(name.as_bytes())?,
+                    HeaderValue::from_str(&value)?,
+                );
+            }
+            body
+        }
+    };
+
+    request
+        .headers_mut()
+        .insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+    *request.body_mut() = body;
+    Ok(())
+}
+
+/// Checks a parsed request against the limits of the host it is addressed to
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn check_request_limits(
+    request: &Request<BytesMut>,
+    framing: BodyFraming,
+    limits: &RequestLimits,
+) -> Result<(), CbltError> {
+    let uri_length = request
+        .uri()
+        .path_and_query()
+        .map(|pq| pq.as_str().len())
+        .unwrap_or(0);
+    if uri_length > limits.max_uri_length {
+        return Err(CbltError::RequestError {
+            details: "URI too long".to_string(),
+            status_code: StatusCode::URI_TOO_LONG,
+        });
+    }
+    let header_bytes: usize = request
+        .headers()
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len() + 4)
+        .sum();
+    if request.headers().len() > limits.max_header_count || header_bytes > limits.max_header_bytes {
+        return Err(headers_too_large());
+    }
+    if let BodyFraming::ContentLength(content_length) = framing {
+        if content_length > limits.max_body_size {
+            return Err(payload_too_large());
+        }
+    }
+    Ok(())
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn uri_length(buf: &[u8]) -> usize {
+    // Request line is "METHOD URI VERSION", the URI may still be incomplete
+    let line_end = buf.iter().position(|b| *b == b'\n').unwrap_or(buf.len());
+    buf[..line_end]
+        .split(|b| *b == b' ')
+        .nth(1)
+        .map(|uri| uri.len())
+        .unwrap_or(0)
+}
+
+fn headers_too_large() -> CbltError {
+    CbltError::RequestError {
+        details: "Request header fields too large".to_string(),
+        status_code: StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+    }
+}
+
+fn payload_too_large() -> CbltError {
+    CbltError::RequestError {
+        details: "Payload too large".to_string(),
+        status_code: StatusCode::PAYLOAD_TOO_LARGE,
+    }
+}
+
+/// Decodes a `Transfer-Encoding: chunked` body, chunk extensions are ignored
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn read_chunked_body<S>(
+    socket: &mut S,
+    buf: &mut BytesMut,
+    limits: &RequestLimits,
+) -> Result<(BytesMut, Vec<(String, String)>), CbltError>
+where
+    S: AsyncReadExt + Unpin,
+{
+    let mut body = BytesMut::new();
+    loop {
+        let (size_len, chunk_size) = loop {
+            match httparse::parse_chunk_size(buf) {
+                Ok(Status::Complete((size_len, chunk_size))) => break (size_len, chunk_size),
+                Ok(Status::Partial) => read_more(socket, buf).await?,
+                Err(_) => {
+                    return Err(CbltError::RequestError {
+                        details: "Invalid chunk size".to_string(),
+                        status_code: StatusCode::BAD_REQUEST,
+                    });
+                }
+            }
+        };
+        let _ = buf.split_to(size_len);
+
+        if chunk_size == 0 {
+            break;
+        }
+        let chunk_size = usize::try_from(chunk_size).map_err(|_| CbltError::RequestError {
+            details: "Chunk too large".to_string(),
+            status_code: StatusCode::BAD_REQUEST,
+        })?;
+        if body.len() + chunk_size > limits.max_body_size {
+            return Err(payload_too_large());
+        }
+
+        // Chunk data is followed by CRLF
+        while buf.len() < chunk_size + 2 {
+            read_more(socket, buf).await?;
+        }
+        body.extend_from_slice(&buf.split_to(chunk_size));
+        if &buf.split_to(2)[..] != b"\r\n" {
+            return Err(CbltError::RequestError {
+                details: "Invalid chunk terminator".to_string(),
+                status_code: StatusCode::BAD_REQUEST,
+            });
+        }
+    }
+
+    // Trailer section ends with an empty line
+    let mut trailers = Vec::new();
+    loop {
+        let mut headers = vec![httparse::EMPTY_HEADER; limits.max_header_count];
+        match httparse::parse_headers(buf, &mut headers) {
+            Ok(Status::Complete((trailer_len, parsed))) => {
+                for header in parsed {
+                    if is_allowed_trailer(header.name) {
+                        if let Ok(value) = str::from_utf8(header.value) {
+                            trailers.push((header.name.to_string(), value.to_string()));
+                        }
+                    }
+                }
+                let _ = buf.split_to(trailer_len);
+                break;
+            }
+            Ok(Status::Partial) => {
+                if buf.len() > limits.max_header_bytes {
+                    return Err(headers_too_large());
+                }
+                read_more(socket, buf).await?
+            }
+            Err(httparse::Error::TooManyHeaders) => {
+                return Err(headers_too_large());
+            }
+            Err(err) => {
+                return Err(CbltError::RequestError {
+                    details: err.to_string(),
+                    status_code: StatusCode::BAD_REQUEST,
+                });
+            }
+        }
+    }
+
+    Ok((body, trailers))
+}
+
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
-pub fn parse_range_header(range_header: &str, file_size: u64) -> Result<(u64, u64), CbltError> {
-    // Expected format: "bytes=START-END"
-    if !range_header.starts_with("bytes=") {
+fn is_allowed_trailer(name: &str) -> bool {
+    // Fields that control framing, routing or authentication must not come from trailers
+    const FORBIDDEN: [&str; 8] = [
+        "content-length",
+        "transfer-encoding",
+        "host",
+        "connection",
+        "trailer",
+        "te",
+        "authorization",
+        "cookie",
+    ];
+    !FORBIDDEN.iter().any(|f| name.eq_ignore_ascii_case(f))
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn read_more<S>(socket: &mut S, buf: &mut BytesMut) -> Result<(), CbltError>
+where
+    S: AsyncReadExt + Unpin,
+{
+    let bytes_read = socket.read_buf(buf).await.unwrap_or(0);
+    if bytes_read == 0 {
+        return Err(CbltError::RequestError {
+            details: "Incomplete request body".to_string(),
+            status_code: StatusCode::BAD_REQUEST,
+        });
+    }
+    Ok(())
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn is_keep_alive(request: &Request<BytesMut>) -> bool {
+    let connection = request
+        .headers()
+        .get(CONNECTION)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+    let has_token = |token: &str| {
+        connection
+            .split(',')
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    };
+
+    match request.version() {
+        Version::HTTP_10 => has_token("keep-alive"),
+        _ => !has_token("close"),
+    }
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn parse_range_header(
+    range_header: &str,
+    file_size: u64,
+) -> Result<Vec<(u64, u64)>, CbltError> {
+    // Expected format: "bytes=START-END, START-, -SUFFIX"
+    let Some(range_values) = range_header.strip_prefix("bytes=") else {
         return Err(CbltError::ResponseError {
             details: "Invalid Range header".to_string(),
             status_code: StatusCode::BAD_REQUEST,
         });
+    };
+
+    let mut ranges = Vec::new();
+    for spec in range_values.split(',').map(str::trim) {
+        if spec.is_empty() {
+            continue;
+        }
+        let parsed = spec.split_once('-').and_then(|(start, end)| {
+            let start = (!start.is_empty()).then(|| start.parse::<u64>());
+            let end = (!end.is_empty()).then(|| end.parse::<u64>());
+            match (start, end) {
+                (Some(Ok(s)), Some(Ok(e))) if s <= e => Some((Some(s), Some(e))),
+                (Some(Ok(s)), None) => Some((Some(s), None)),
+                (None, Some(Ok(e))) => Some((None, Some(e))),
+                _ => None,
+            }
+        });
+        let range = match parsed {
+            Some((Some(s), Some(e))) => (s < file_size).then(|| (s, e.min(file_size - 1))),
+            Some((Some(s), None)) => (s < file_size).then(|| (s, file_size - 1)),
+            Some((None, Some(e))) => {
+                (e != 0 && file_size != 0).then(|| (file_size.saturating_sub(e), file_size - 1))
+            }
+            _ => {
+                return Err(CbltError::ResponseError {
+                    details: "Invalid Range header format".to_string(),
+                    status_code: StatusCode::BAD_REQUEST,
+                });
+            }
+        };
+        // Unsatisfiable ranges are skipped as long as another one can be served
+        ranges.extend(range);
     }
 
-    let range_values = &range_header[6..]; // Remove "bytes="
-    let parts: Vec<&str> = range_values.split('-').collect();
-    if parts.len() != 2 {
+    if ranges.is_empty() {
         return Err(CbltError::ResponseError {
-            details: "Invalid Range header format".to_string(),
-            status_code: StatusCode::BAD_REQUEST,
+            details: "Invalid Range header values".to_string(),
+            status_code: StatusCode::RANGE_NOT_SATISFIABLE,
         });
     }
 
-    let start = parts[0].parse::<u64>().ok();
-    let end = parts[1].parse::<u64>().ok();
+    Ok(coalesce_ranges(ranges))
+}
 
-    let (start, end) = match (start, end) {
-        (Some(s), Some(e)) if s <= e && e < file_size => (s, e),
-        (Some(s), None) if s < file_size => (s, file_size - 1),
-        (None, Some(e)) if e != 0 => (file_size - e, file_size - 1),
-        _ => {
-            return Err(CbltError::ResponseError {
-                details: "Invalid Range header values".to_string(),
-                status_code: StatusCode::RANGE_NOT_SATISFIABLE,
-            });
+/// Merges overlapping and adjacent ranges, so a file part is never sent twice
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn coalesce_ranges(mut ranges: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    ranges.sort_unstable();
+    let mut coalesced: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match coalesced.last_mut() {
+            Some((_, last_end)) if start <= last_end.saturating_add(1) => {
+                *last_end = (*last_end).max(end);
+            }
+            _ => coalesced.push((start, end)),
         }
+    }
+    coalesced
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::RequestLimits;
+    use crate::request::{is_keep_alive, parse_range_header, read_request_body, socket_to_request};
+    use bytes::BytesMut;
+    use http::{Request, StatusCode};
+    use std::error::Error;
+    use tokio::io::{AsyncWriteExt, DuplexStream};
+
+    const LIMITS: RequestLimits = RequestLimits {
+        max_header_count: 8,
+        max_header_bytes: 1024,
+        max_uri_length: 64,
+        max_body_size: 16,
     };
 
-    Ok((start, end))
+    async fn read_request(
+        server: &mut DuplexStream,
+        buf: &mut BytesMut,
+    ) -> Result<Option<Request<BytesMut>>, crate::error::CbltError> {
+        match socket_to_request(server, buf, &LIMITS).await? {
+            Some((mut request, framing)) => {
+                read_request_body(server, buf, &mut request, framing, &LIMITS).await?;
+                Ok(Some(request))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn status_of(raw: &[u8]) -> Result<StatusCode, Box<dyn Error>> {
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        client.write_all(raw).await?;
+        client.shutdown().await?;
+        match read_request(&mut server, &mut BytesMut::new()).await {
+            Ok(_) => Ok(StatusCode::OK),
+            Err(crate::error::CbltError::RequestError { status_code, .. }) => Ok(status_code),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_requests() -> Result<(), Box<dyn Error>> {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client
+            .write_all(
+                b"POST /a HTTP/1.1\r\nHost: example.com\r\nContent-Length: 3\r\n\r\nabc\
+                  GET /b HTTP/1.0\r\nHost: example.com\r\n\r\n",
+            )
+            .await?;
+        client.shutdown().await?;
+
+        let mut buf = BytesMut::new();
+        let first = read_request(&mut server, &mut buf)
+            .await?
+            .ok_or("no request")?;
+        assert_eq!(first.uri().path(), "/a");
+        assert_eq!(first.body().as_ref(), b"abc");
+        assert_eq!(first.headers().get_all("Content-Length").iter().count(), 1);
+        assert!(is_keep_alive(&first));
+
+        let second = read_request(&mut server, &mut buf)
+            .await?
+            .ok_or("no request")?;
+        assert_eq!(second.uri().path(), "/b");
+        assert!(!is_keep_alive(&second));
+
+        assert!(read_request(&mut server, &mut buf).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chunked_body() -> Result<(), Box<dyn Error>> {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client
+            .write_all(
+                b"POST /upload HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\n\r\n\
+                  5;name=value\r\nhello\r\n6\r\n world\r\n0\r\nX-Checksum: 42\r\n\r\n",
+            )
+            .await?;
+        client.shutdown().await?;
+
+        let mut buf = BytesMut::new();
+        let request = read_request(&mut server, &mut buf)
+            .await?
+            .ok_or("no request")?;
+        assert_eq!(request.body().as_ref(), b"hello world");
+        assert_eq!(request.headers()["Content-Length"], "11");
+        assert_eq!(request.headers()["X-Checksum"], "42");
+        assert!(request.headers().get("Transfer-Encoding").is_none());
+        assert!(buf.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_request_limits() -> Result<(), Box<dyn Error>> {
+        let long_uri = format!("GET /{} HTTP/1.1\r\nHost: a\r\n\r\n", "a".repeat(100));
+        assert_eq!(
+            status_of(long_uri.as_bytes()).await?,
+            StatusCode::URI_TOO_LONG
+        );
+
+        let many_headers = format!("GET / HTTP/1.1\r\n{}\r\n", "X-A: b\r\n".repeat(10));
+        assert_eq!(
+            status_of(many_headers.as_bytes()).await?,
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+        );
+
+        let big_header = format!("GET / HTTP/1.1\r\nX-A: {}\r\n\r\n", "b".repeat(2000));
+        assert_eq!(
+            status_of(big_header.as_bytes()).await?,
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+        );
+
+        assert_eq!(
+            status_of(b"POST / HTTP/1.1\r\nContent-Length: 17\r\n\r\n").await?,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        assert_eq!(
+            status_of(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n11\r\n").await?,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_range_header() -> Result<(), Box<dyn Error>> {
+        assert_eq!(parse_range_header("bytes=0-499", 1000)?, vec![(0, 499)]);
+        assert_eq!(parse_range_header("bytes=500-", 1000)?, vec![(500, 999)]);
+        assert_eq!(parse_range_header("bytes=-100", 1000)?, vec![(900, 999)]);
+        assert_eq!(parse_range_header("bytes=-5000", 1000)?, vec![(0, 999)]);
+        assert_eq!(
+            parse_range_header("bytes=900-5000", 1000)?,
+            vec![(900, 999)]
+        );
+        assert_eq!(
+            parse_range_header("bytes=500-599, 0-99,-100", 1000)?,
+            vec![(0, 99), (500, 599), (900, 999)]
+        );
+        // Overlapping and adjacent ranges are merged, unsatisfiable ones dropped
+        assert_eq!(
+            parse_range_header("bytes=0-99, 50-149, 150-199, 2000-", 1000)?,
+            vec![(0, 199)]
+        );
+
+        let status = |header: &str| match parse_range_header(header, 1000) {
+            Err(crate::error::CbltError::ResponseError { status_code, .. }) => Some(status_code),
+            _ => None,
+        };
+        assert_eq!(
+            status("bytes=1000-"),
+            Some(StatusCode::RANGE_NOT_SATISFIABLE)
+        );
+        assert_eq!(status("bytes=-0"), Some(StatusCode::RANGE_NOT_SATISFIABLE));
+        assert_eq!(status("bytes=5-1"), Some(StatusCode::BAD_REQUEST));
+        assert_eq!(status("bytes=a-b"), Some(StatusCode::BAD_REQUEST));
+        assert_eq!(status("items=0-1"), Some(StatusCode::BAD_REQUEST));
+        Ok(())
+    }
 }
@@ -1,28 +1,117 @@
+use crate::compression::encoder;
+use crate::config::{Encoding, HeaderOperation};
 use crate::error::CbltError;
-use async_compression::tokio::write::GzipEncoder;
+use crate::request::{is_keep_alive, BUF_SIZE};
 use bytes::BytesMut;
-use http::{Request, Response, StatusCode};
+use http::header::{CONNECTION, CONTENT_ENCODING, CONTENT_LENGTH, ETAG, TRANSFER_ENCODING};
+use http::response::Parts;
+use http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Version};
 use log::{debug, info};
-use std::fmt::Debug;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
 use std::path::PathBuf;
-use std::pin;
 use tokio::fs::File;
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
 #[cfg(feature = "trace")]
 use tracing::instrument;
 
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
 pub async fn send_response_file<S>(
     mut socket: S,
-    response: Response<impl AsyncRead + Debug + AsyncWrite>,
+    response: Response<impl AsyncRead + Unpin + Send + 'static>,
     req: &Request<BytesMut>,
+    encoding: Option<(Encoding, Option<i32>)>,
 ) -> Result<(), CbltError>
 where
     S: AsyncWriteExt + Unpin,
 {
-    let (parts, mut b) = response.into_parts();
-    let mut body = pin::pin!(b);
+    let (mut parts, mut body) = response.into_parts();
+    finish_headers(&mut parts.headers, req);
 
+    let Some((encoding, level)) = encoding else {
+        write_response_head(&mut socket, &parts).await?;
+        if req.method() == Method::HEAD {
+            return Ok(());
+        }
+        tokio::io::copy(&mut body, &mut socket).await?;
+
+        // Ensure all data is flushed
+        socket.flush().await?;
+        return Ok(());
+    };
+
+    #[cfg(debug_assertions)]
+    debug!("Content-Encoding: {}", encoding.as_str());
+    // The compressed length is not known upfront
+    parts.headers.remove(CONTENT_LENGTH);
+    parts.headers.insert(
+        CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.as_str()),
+    );
+    // The encoded representation is not byte-identical to the file the strong ETag describes
+    if let Some(etag) = parts.headers.get(ETAG) {
+        if !etag.as_bytes().starts_with(b"W/") {
+            let weak = [b"W/", etag.as_bytes()].concat();
+            parts.headers.insert(ETAG, HeaderValue::from_bytes(&weak)?);
+        }
+    }
+    // HTTP/1.0 has no chunked encoding, there the end of the body is marked by closing the connection
+    let chunked = req.version() >= Version::HTTP_11;
+    if chunked {
+        parts
+            .headers
+            .insert(TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+    } else {
+        parts
+            .headers
+            .insert(CONNECTION, HeaderValue::from_static("close"));
+    }
+    write_response_head(&mut socket, &parts).await?;
+
+    if req.method() != Method::HEAD {
+        let mut encoder = encoder(BufReader::new(body), encoding, level);
+        if chunked {
+            copy_chunked(&mut encoder, &mut socket).await?;
+        } else {
+            tokio::io::copy(&mut encoder, &mut socket).await?;
+        }
+    }
+
+    // Ensure all data is flushed
+    socket.flush().await?;
+    if !chunked {
+        socket.shutdown().await?;
+    }
+
+    Ok(())
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn copy_chunked<R, S>(reader: &mut R, socket: &mut S) -> Result<(), CbltError>
+where
+    R: AsyncRead + Unpin,
+    S: AsyncWriteExt + Unpin,
+{
+    let mut buf = vec![0; BUF_SIZE];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        socket.write_all(format!("{:x}\r\n", n).as_bytes()).await?;
+        socket.write_all(&buf[..n]).await?;
+        socket.write_all(b"\r\n").await?;
+    }
+    socket.write_all(b"0\r\n\r\n").await?;
+    Ok(())
+}
+
+/// Writes the status line and headers of a streamed response
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn write_response_head<S>(socket: &mut S, parts: &Parts) -> Result<(), CbltError>
+where
+    S: AsyncWriteExt + Unpin,
+{
     // Write status line without allocation
     socket.write_all(b"HTTP/1.1 ").await?;
     let mut itoa_buf = itoa::Buffer::new();
@@ -33,11 +122,6 @@ where
         .write_all(parts.status.canonical_reason().unwrap_or("").as_bytes())
         .await?;
     socket.write_all(b"\r\n").await?;
-    let gzip_supported = gzip_support_detect(req);
-    if gzip_supported {
-        // socket.write_all(b"Content-Encoding: gzip").await?;
-        // socket.write_all(b"\r\n").await?;
-    }
 
     // Write headers without allocation
     for (key, value) in parts.headers.iter() {
@@ -52,20 +136,6 @@ where
 
     // Ensure all headers are flushed
     socket.flush().await?;
-
-    if gzip_supported {
-        #[cfg(debug_assertions)]
-        debug!("Gzip supported");
-        let gzip_stream = GzipEncoder::new(body);
-        let mut gzip_reader = tokio::io::BufReader::new(gzip_stream);
-        tokio::io::copy(&mut gzip_reader, &mut socket).await?;
-    } else {
-        tokio::io::copy(&mut body, &mut socket).await?;
-    }
-
-    // Ensure all data is flushed
-    socket.flush().await?;
-
     Ok(())
 }
 
@@ -84,6 +154,114 @@ pub async fn ranged_file_response(
     use tokio::io::AsyncSeekExt;
     file.seek(std::io::SeekFrom::Start(start)).await?;
 
+    let content_range = content_range(start, end, file_size)?;
+
+    let mime_type = mime_guess::from_path(file_path)
+        .first_or_octet_stream()
+        .to_string();
+    Ok(Response::builder()
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header("Content-Length", content_length)
+        .header("Content-Range", content_range.as_str())
+        .header("Content-Type", mime_type)
+        .body(file)?)
+}
+
+/// Body of a `multipart/byteranges` response: one part per range of the same file
+#[derive(Debug)]
+pub struct ByteRanges {
+    file: File,
+    file_path: PathBuf,
+    file_size: u64,
+    ranges: Vec<(u64, u64)>,
+    part_heads: Vec<String>,
+    closing: String,
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn multipart_ranged_file_response(
+    file: File,
+    file_path: &PathBuf,
+    file_size: u64,
+    ranges: Vec<(u64, u64)>,
+) -> Result<Response<ByteRanges>, CbltError> {
+    let boundary = format!("{:016x}", RandomState::new().build_hasher().finish());
+    let mime_type = mime_guess::from_path(file_path)
+        .first_or_octet_stream()
+        .to_string();
+
+    let mut part_heads = Vec::with_capacity(ranges.len());
+    let mut content_length = 0;
+    for &(start, end) in &ranges {
+        let part_head = format!(
+            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+            boundary,
+            mime_type,
+            content_range(start, end, file_size)?
+        );
+        content_length += part_head.len() as u64 + end - start + 1;
+        part_heads.push(part_head);
+    }
+    let closing = format!("\r\n--{}--\r\n", boundary);
+    content_length += closing.len() as u64;
+
+    Ok(Response::builder()
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header("Content-Length", content_length)
+        .header(
+            "Content-Type",
+            format!("multipart/byteranges; boundary={}", boundary),
+        )
+        .body(ByteRanges {
+            file,
+            file_path: file_path.clone(),
+            file_size,
+            ranges,
+            part_heads,
+            closing,
+        })?)
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn send_multipart_response<S>(
+    mut socket: S,
+    response: Response<ByteRanges>,
+    req: &Request<BytesMut>,
+) -> Result<(), CbltError>
+where
+    S: AsyncWriteExt + Unpin,
+{
+    let (mut parts, body) = response.into_parts();
+    finish_headers(&mut parts.headers, req);
+    write_response_head(&mut socket, &parts).await?;
+
+    if req.method() == Method::HEAD {
+        return Ok(());
+    }
+
+    for (range, part_head) in body.ranges.into_iter().zip(body.part_heads) {
+        socket.write_all(part_head.as_bytes()).await?;
+        // The clone shares the file cursor, parts are sent one after another
+        let part = ranged_file_response(
+            body.file.try_clone().await?,
+            &body.file_path,
+            body.file_size,
+            range,
+        )
+        .await?;
+        let mut part_body = part.into_body().take(range.1 - range.0 + 1);
+        tokio::io::copy(&mut part_body, &mut socket).await?;
+    }
+    socket.write_all(body.closing.as_bytes()).await?;
+
+    // Ensure all data is flushed
+    socket.flush().await?;
+
+    Ok(())
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn content_range(start: u64, end: u64, file_size: u64) -> Result<heapless::String<200>, CbltError> {
     let mut content_range: heapless::String<200> = heapless::String::new();
     content_range
         .push_str("bytes ")
@@ -104,27 +282,45 @@ pub async fn ranged_file_response(
         .push_str(file_size.to_string().as_str())
         .map_err(|_| CbltError::HeaplessError {})?;
 
-    let mime_type = mime_guess::from_path(file_path)
-        .first_or_octet_stream()
-        .to_string();
-    Ok(Response::builder()
-        .status(StatusCode::PARTIAL_CONTENT)
-        .header("Content-Length", content_length)
-        .header("Content-Range", content_range.as_str())
-        .header("Content-Type", mime_type)
-        .body(file)?)
+    Ok(content_range)
 }
 
+/// Header operations of the `header` directives matching a request, attached to it
+/// as an extension by `request_process`
+#[derive(Debug, Clone, Default)]
+pub struct ResponseHeaders(pub Vec<HeaderOperation>);
+
+/// Applies the `header` directives of the request, then tells the client whether
+/// the connection stays open after this response
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
-fn gzip_support_detect(req_opt: &Request<BytesMut>) -> bool {
-    let accept_encoding = req_opt
-        .headers()
-        .get(http::header::ACCEPT_ENCODING)
-        .and_then(|value| value.to_str().ok());
-
-    accept_encoding
-        .map(|encodings| encodings.contains("gzip"))
-        .unwrap_or(false)
+pub fn finish_headers(headers: &mut HeaderMap, req: &Request<BytesMut>) {
+    if let Some(ResponseHeaders(operations)) = req.extensions().get::<ResponseHeaders>() {
+        apply_header_operations(headers, operations);
+    }
+    if is_keep_alive(req) {
+        if req.version() == Version::HTTP_10 {
+            headers.insert(CONNECTION, HeaderValue::from_static("keep-alive"));
+        }
+    } else {
+        headers.insert(CONNECTION, HeaderValue::from_static("close"));
+    }
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn apply_header_operations(headers: &mut HeaderMap, operations: &[HeaderOperation]) {
+    for operation in operations {
+        match operation {
+            HeaderOperation::Set(name, value) => {
+                headers.insert(name.clone(), value.clone());
+            }
+            HeaderOperation::Add(name, value) => {
+                headers.append(name.clone(), value.clone());
+            }
+            HeaderOperation::Remove(name) => {
+                headers.remove(name);
+            }
+        }
+    }
 }
 
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
@@ -146,12 +342,39 @@ pub fn log_request_response(request: &Request<BytesMut>, status_code: StatusCode
     );
 }
 
+/// Sends a buffered response with the connection and HEAD handling of the request applied
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn respond<S>(
+    socket: &mut S,
+    request: &Request<BytesMut>,
+    mut response: Response<BytesMut>,
+) -> Result<(), CbltError>
+where
+    S: AsyncWriteExt + Unpin,
+{
+    finish_headers(response.headers_mut(), request);
+    if request.method() == Method::HEAD {
+        let content_length = response.body().len();
+        response
+            .headers_mut()
+            .insert(CONTENT_LENGTH, HeaderValue::from(content_length));
+        response.body_mut().clear();
+    }
+    send_response(socket, response).await
+}
+
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
 pub async fn send_response<S>(socket: &mut S, response: Response<BytesMut>) -> Result<(), CbltError>
 where
     S: AsyncWriteExt + Unpin,
 {
-    let (parts, body) = response.into_parts();
+    let (mut parts, body) = response.into_parts();
+    // A 304 carries no body, its Content-Length would describe the full representation
+    if !parts.headers.contains_key(CONTENT_LENGTH) && parts.status != StatusCode::NOT_MODIFIED {
+        parts
+            .headers
+            .insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+    }
 
     // Estimate capacity to reduce reallocations
     let mut resp_bytes = Vec::with_capacity(128 + body.len());
@@ -178,6 +401,7 @@ where
     resp_bytes.extend_from_slice(&body);
 
     socket.write_all(&resp_bytes).await?;
+    socket.flush().await?;
 
     Ok(())
 }
@@ -189,9 +413,14 @@ pub fn error_response(status: StatusCode) -> Result<Response<BytesMut>, CbltErro
         StatusCode::FORBIDDEN => "Forbidden",
         StatusCode::NOT_FOUND => "Not found",
         StatusCode::METHOD_NOT_ALLOWED => "Method not allowed",
+        StatusCode::REQUEST_TIMEOUT => "Request timeout",
+        StatusCode::PRECONDITION_FAILED => "Precondition failed",
+        StatusCode::PAYLOAD_TOO_LARGE => "Payload too large",
+        StatusCode::URI_TOO_LONG => "URI too long",
+        StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE => "Request header fields too large",
         StatusCode::INTERNAL_SERVER_ERROR => "Internal server error",
         StatusCode::BAD_GATEWAY => "Bad gateway",
-        _ => "Unknown error",
+        _ => status.canonical_reason().unwrap_or("Unknown error"),
     };
     let bytes = BytesMut::from(msg);
     Ok(Response::builder().status(status).body(bytes)?)
@@ -1,12 +1,29 @@
+use crate::response::{apply_header_operations, finish_headers, ResponseHeaders};
+use crate::upstream::{
+    relay_body, BackendStream, Connection, ConnectionPool, ResponseFraming, UpstreamTls,
+};
 use crate::{matches_pattern, CbltError};
 use bytes::BytesMut;
-use http::{Request, StatusCode};
+use http::header::{
+    CONNECTION, CONTENT_LENGTH, COOKIE, EXPECT, FORWARDED, HOST, SET_COOKIE, TRANSFER_ENCODING,
+    UPGRADE,
+};
+use http::uri::Scheme;
+use http::{HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode};
 use log::debug;
 use log::error;
+use log::info;
+use std::borrow::Cow;
+use std::fmt::Write as _;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 #[cfg(feature = "trace")]
 use tracing::instrument;
 pub const HEAPLESS_STRING_SIZE: usize = 100;
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+const KEEP_ALIVE: HeaderName = HeaderName::from_static("keep-alive");
+const PROXY_CONNECTION: HeaderName = HeaderName::from_static("proxy-connection");
 
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
 pub async fn proxy_directive<S>(
@@ -19,31 +36,27 @@ pub async fn proxy_directive<S>(
 where
     S: AsyncReadExt + AsyncWriteExt + Unpin,
 {
-    let options = match directive {
+    let (pattern, options) = match directive {
         Directive::ReverseProxy {
-            pattern: _,
+            pattern,
             destinations: _,
             options,
-        } => options,
+        } => (pattern, options),
         _ => {
             return Err(CbltError::DirectiveNotMatched);
         }
     };
-    for (pattern, reverse_proxy_state) in states {
+    // States are keyed by pattern, options of another reverse_proxy must not apply
+    if let Some(reverse_proxy_state) = states.get(pattern) {
         if matches_pattern(pattern, request.uri().path()) {
+            let upstream_uri = upstream_uri(request, options);
             loop {
-                match reverse_proxy_state.get_next_backend(addr).await {
+                match reverse_proxy_state.get_next_backend(addr, request).await {
                     Ok(backend) => {
                         #[cfg(debug_assertions)]
                         debug!("Selected backend: {:?}", backend);
-                        let mut dest_uri: heapless::String<{ 2 * HEAPLESS_STRING_SIZE }> =
-                            heapless::String::new();
-                        dest_uri
-                            .push_str(backend.address.as_str())
-                            .map_err(|_| CbltError::HeaplessError {})?;
-                        dest_uri
-                            .push_str(request.uri().path())
-                            .map_err(|_| CbltError::HeaplessError {})?;
+                        let _in_flight = reverse_proxy_state.start_request(&backend);
+                        let dest_uri = format!("{}{}", backend.address, upstream_uri);
 
                         #[cfg(debug_assertions)]
                         debug!("Destination URI: {}", dest_uri);
@@ -80,131 +93,140 @@ where
                         #[cfg(debug_assertions)]
                         debug!("Connecting to backend at {}", backend_addr);
 
-                        // Establish a TCP connection to the backend with retries
-                        let timeout_duration = Duration::from_secs(options.lb_timeout);
-                        let mut retries = options.lb_retries;
-                        let mut backend_stream_result = Err(CbltError::ResponseError {
-                            details: "Failed to connect to backend".to_string(),
-                            status_code: StatusCode::BAD_GATEWAY,
-                        });
-
-                        while retries > 0 {
-                            match timeout(
-                                timeout_duration,
-                                TcpStream::connect(backend_addr.as_str()),
+                        let connect = || {
+                            connect_backend(
+                                reverse_proxy_state,
+                                options,
+                                &dest_uri_parsed,
+                                host,
+                                backend_addr.as_str(),
                             )
-                            .await
-                            {
-                                Ok(connect_result) => match connect_result {
-                                    Ok(stream) => {
-                                        backend_stream_result = Ok(stream);
-                                        break;
-                                    }
-                                    Err(e) => {
-                                        #[cfg(debug_assertions)]
-                                        error!("Failed to connect to backend: {}", e);
-                                        retries -= 1;
+                        };
+                        let mut connection =
+                            match reverse_proxy_state.pool.take(backend.backend_index) {
+                                Some(connection) => connection,
+                                None => match connect().await {
+                                    Ok(connection) => connection,
+                                    Err(_) => {
+                                        // Mark the backend as dead and continue to the next backend
+                                        reverse_proxy_state.set_dead_backend(&backend).await?;
+                                        continue; // Try the next backend
                                     }
                                 },
-                                Err(e) => {
-                                    #[cfg(debug_assertions)]
-                                    error!("Connection to backend timed out: {}", e);
-                                    retries -= 1;
-                                }
-                            }
-                        }
+                            };
+                        // Backend is alive, update its state
+                        reverse_proxy_state.set_alive_backend(&backend).await?;
 
-                        match backend_stream_result {
-                            Ok(mut backend_stream) => {
-                                // Backend is alive, update its state
-                                reverse_proxy_state.set_alive_backend(&backend).await?;
-
-                                // Send the initial request to the backend
-                                let request_bytes = request_to_bytes(request)?;
-                                backend_stream
-                                    .write_all(&request_bytes)
-                                    .await
-                                    .map_err(|e| CbltError::ResponseError {
-                                        details: e.to_string(),
-                                        status_code: StatusCode::BAD_GATEWAY,
-                                    })?;
-
-                                // Read the response from the backend
-                                let mut backend_buf = BytesMut::with_capacity(8192);
-                                let header_len =
-                                    get_header_len(&mut backend_stream, &mut backend_buf).await?;
-
-                                // Send the response headers back to the client
-                                socket.write_all(&backend_buf[..header_len]).await.map_err(
-                                    |e| CbltError::ResponseError {
-                                        details: e.to_string(),
-                                        status_code: StatusCode::BAD_GATEWAY,
-                                    },
-                                )?;
-
-                                // If there's any body data already read, send it
-                                if backend_buf.len() > header_len {
-                                    socket.write_all(&backend_buf[header_len..]).await.map_err(
-                                        |e| CbltError::ResponseError {
-                                            details: e.to_string(),
-                                            status_code: StatusCode::BAD_GATEWAY,
-                                        },
-                                    )?;
-                                }
+                        let placeholders = Placeholders {
+                            request,
+                            remote: addr,
+                            upstream: backend.address.as_str(),
+                            upstream_hostport: backend_addr.as_str(),
+                        };
+                        let request_bytes =
+                            request_to_bytes(request, &upstream_uri, options, &placeholders)?;
 
-                                let (mut backend_read_half, mut backend_write_half) =
-                                    backend_stream.split();
-                                let (mut client_read_half, mut client_write_half) =
-                                    tokio::io::split(socket);
-
-                                let client_to_backend = async {
-                                    let result = tokio::io::copy(
-                                        &mut client_read_half,
-                                        &mut backend_write_half,
-                                    )
-                                    .await;
-                                    backend_write_half.shutdown().await.ok();
-                                    result
-                                };
-
-                                let backend_to_client = async {
-                                    let result = tokio::io::copy(
-                                        &mut backend_read_half,
-                                        &mut client_write_half,
-                                    )
-                                    .await;
-                                    client_write_half.shutdown().await.ok();
-                                    result
-                                };
-
-                                let (client_to_backend_res, backend_to_client_res) =
-                                    tokio::join!(client_to_backend, backend_to_client);
-                                match (client_to_backend_res, backend_to_client_res) {
-                                    (Ok(_), Ok(_)) => {
-                                        return Ok(StatusCode::OK);
-                                    }
-                                    _ => {
-                                        return Err(CbltError::ResponseError {
-                                            details:
-                                                "Failed to copy data between client and backend"
-                                                    .to_string(),
-                                            status_code: StatusCode::BAD_GATEWAY,
-                                        });
-                                    }
+                        let mut backend_buf = BytesMut::with_capacity(8192);
+                        let mut sent = send_request(
+                            &mut connection.stream,
+                            &request_bytes,
+                            &mut backend_buf,
+                            options.response_timeout,
+                        )
+                        .await;
+                        // A pooled connection may have been closed by the backend meanwhile,
+                        // idempotent requests are sent again on a new one
+                        if sent.is_err() && connection.reused && request.method().is_idempotent() {
+                            backend_buf.clear();
+                            connection = match connect().await {
+                                Ok(connection) => connection,
+                                Err(_) => {
+                                    reverse_proxy_state.set_dead_backend(&backend).await?;
+                                    continue;
                                 }
+                            };
+                            sent = send_request(
+                                &mut connection.stream,
+                                &request_bytes,
+                                &mut backend_buf,
+                                options.response_timeout,
+                            )
+                            .await;
+                        }
+                        let header_len = match sent {
+                            Ok(header_len) => header_len,
+                            Err(err) => {
+                                reverse_proxy_state.record_failure(&backend).await?;
+                                return Err(err);
                             }
-                            Err(_) => {
-                                // Mark the backend as dead and continue to the next backend
-                                reverse_proxy_state.set_dead_backend(&backend).await?;
-                                continue; // Try the next backend
+                        };
+
+                        // Send the response head back to the client, interim 1xx responses
+                        // are passed on before the final one
+                        let mut operations = placeholders.expand_all(&options.header_down)?;
+                        if backend.set_cookie {
+                            let secure = placeholders.scheme() == "https";
+                            if let Some(cookie) =
+                                reverse_proxy_state.sticky_cookie(&backend, secure)?
+                            {
+                                operations.push(HeaderOperation::Add(SET_COOKIE, cookie));
+                            }
+                        }
+                        let mut header_len = header_len;
+                        let head = loop {
+                            let head =
+                                response_head(&backend_buf[..header_len], request, &operations)?;
+                            let _ = backend_buf.split_to(header_len);
+                            socket.write_all(&head.bytes).await?;
+                            if !head.status.is_informational()
+                                || head.framing == ResponseFraming::Upgrade
+                            {
+                                break head;
                             }
+                            header_len = match get_header_len(
+                                &mut connection.stream,
+                                &mut backend_buf,
+                            )
+                            .await
+                            {
+                                Ok(header_len) => header_len,
+                                Err(err) => {
+                                    reverse_proxy_state.record_failure(&backend).await?;
+                                    return Err(err);
+                                }
+                            };
+                        };
+                        if reverse_proxy_state.is_failure_status(head.status) {
+                            reverse_proxy_state.record_failure(&backend).await?;
                         }
+
+                        if head.framing == ResponseFraming::Upgrade {
+                            tunnel(connection.stream, &backend_buf, socket).await?;
+                            return Ok(head.status);
+                        }
+                        if let Err(err) = relay_body(
+                            &mut connection.stream,
+                            &mut backend_buf,
+                            socket,
+                            head.framing,
+                        )
+                        .await
+                        {
+                            reverse_proxy_state.record_failure(&backend).await?;
+                            return Err(err);
+                        }
+                        if head.framing == ResponseFraming::UntilClose {
+                            // Without a length the client reads the body up to the close
+                            socket.shutdown().await?;
+                        } else if head.keep_alive && backend_buf.is_empty() {
+                            reverse_proxy_state
+                                .pool
+                                .put(backend.backend_index, connection);
+                        }
+                        return Ok(head.status);
                     }
                     Err(_) => {
-                        return Err(CbltError::ResponseError {
-                            details: "No healthy backends".to_string(),
-                            status_code: StatusCode::BAD_GATEWAY,
-                        });
+                        return Err(no_healthy_backends());
                     }
                 }
             }
@@ -213,24 +235,170 @@ where
 
     Err(CbltError::DirectiveNotMatched)
 }
+
+/// Opens a connection to the backend, with a TLS handshake for https:// destinations
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn connect_backend(
+    reverse_proxy_state: &ReverseProxyState,
+    options: &ReverseProxyOptions,
+    dest_uri: &http::Uri,
+    host: &str,
+    backend_addr: &str,
+) -> Result<Connection, CbltError> {
+    // Establish a TCP connection to the backend with retries
+    let timeout_duration = Duration::from_secs(options.lb_timeout);
+    let mut retries = options.lb_retries;
+    let mut backend_stream_result = Err(CbltError::ResponseError {
+        details: "Failed to connect to backend".to_string(),
+        status_code: StatusCode::BAD_GATEWAY,
+    });
+
+    while retries > 0 {
+        match timeout(timeout_duration, TcpStream::connect(backend_addr)).await {
+            Ok(connect_result) => match connect_result {
+                Ok(stream) => {
+                    backend_stream_result = Ok(stream);
+                    break;
+                }
+                Err(e) => {
+                    #[cfg(debug_assertions)]
+                    error!("Failed to connect to backend: {}", e);
+                    retries -= 1;
+                }
+            },
+            Err(e) => {
+                #[cfg(debug_assertions)]
+                error!("Connection to backend timed out: {}", e);
+                retries -= 1;
+            }
+        }
+    }
+    let stream = backend_stream_result?;
+
+    // https:// backends get a TLS handshake on top of the connection
+    let stream = match (&reverse_proxy_state.upstream_tls, dest_uri.scheme_str()) {
+        (Some(upstream_tls), Some("https")) => {
+            match timeout(timeout_duration, upstream_tls.connect(host, stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    #[cfg(debug_assertions)]
+                    error!("TLS handshake with backend failed: {}", e);
+                    return Err(e);
+                }
+                Err(_) => {
+                    #[cfg(debug_assertions)]
+                    error!("TLS handshake with backend timed out");
+                    return Err(CbltError::ResponseError {
+                        details: "TLS handshake timeout".to_string(),
+                        status_code: StatusCode::BAD_GATEWAY,
+                    });
+                }
+            }
+        }
+        _ => BackendStream::Plain(stream),
+    };
+    Ok(Connection::new(stream))
+}
+
+/// Writes the request to the backend and reads up to the end of the response head,
+/// within `response_timeout` if one is set
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn send_request(
+    backend_stream: &mut BackendStream,
+    request_bytes: &[u8],
+    backend_buf: &mut BytesMut,
+    response_timeout: Option<Duration>,
+) -> Result<usize, CbltError> {
+    backend_stream
+        .write_all(request_bytes)
+        .await
+        .map_err(|e| CbltError::ResponseError {
+            details: e.to_string(),
+            status_code: StatusCode::BAD_GATEWAY,
+        })?;
+    match response_timeout {
+        Some(response_timeout) => timeout(
+            response_timeout,
+            get_header_len(backend_stream, backend_buf),
+        )
+        .await
+        .map_err(|_| CbltError::ResponseError {
+            details: "Backend response timed out".to_string(),
+            status_code: StatusCode::GATEWAY_TIMEOUT,
+        })?,
+        None => get_header_len(backend_stream, backend_buf).await,
+    }
+}
+
+/// Passes bytes both ways after the backend switched protocols, e.g. for WebSocket
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn tunnel<S>(
+    backend_stream: BackendStream,
+    backend_buf: &[u8],
+    socket: &mut S,
+) -> Result<(), CbltError>
+where
+    S: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    // If there's any data already read, send it
+    socket.write_all(backend_buf).await?;
+
+    let (mut backend_read_half, mut backend_write_half) = tokio::io::split(backend_stream);
+    let (mut client_read_half, mut client_write_half) = tokio::io::split(socket);
+
+    let client_to_backend = async {
+        let result = tokio::io::copy(&mut client_read_half, &mut backend_write_half).await;
+        backend_write_half.shutdown().await.ok();
+        result
+    };
+
+    let backend_to_client = async {
+        let result = tokio::io::copy(&mut backend_read_half, &mut client_write_half).await;
+        client_write_half.shutdown().await.ok();
+        result
+    };
+
+    let (client_to_backend_res, backend_to_client_res) =
+        tokio::join!(client_to_backend, backend_to_client);
+    client_to_backend_res?;
+    backend_to_client_res?;
+    Ok(())
+}
+
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
-fn request_to_bytes(request: &Request<BytesMut>) -> Result<Vec<u8>, CbltError> {
+fn request_to_bytes(
+    request: &Request<BytesMut>,
+    uri: &str,
+    options: &ReverseProxyOptions,
+    placeholders: &Placeholders,
+) -> Result<Vec<u8>, CbltError> {
     let mut buf = Vec::new();
     // Write request line
     buf.extend_from_slice(request.method().as_str().as_bytes());
     buf.extend_from_slice(b" ");
-    buf.extend_from_slice(
-        request
-            .uri()
-            .path_and_query()
-            .map(|pq| pq.as_str())
-            .unwrap_or("/")
-            .as_bytes(),
-    );
+    buf.extend_from_slice(uri.as_bytes());
     buf.extend_from_slice(b" HTTP/1.1\r\n");
 
-    // Write headers
-    for (key, value) in request.headers() {
+    // Write headers, hop-by-hop ones belong to the client connection except for an upgrade
+    let mut headers = request.headers().clone();
+    let upgrade = has_connection_token(&headers, "upgrade")
+        .then(|| headers.get(UPGRADE).cloned())
+        .flatten();
+    remove_hop_by_hop(&mut headers);
+    // The body is read already, so the backend has nothing to wait for
+    headers.remove(EXPECT);
+    if let Some(upgrade) = upgrade {
+        headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+        headers.insert(UPGRADE, upgrade);
+    }
+    forwarded_headers(
+        &mut headers,
+        placeholders.remote,
+        placeholders.scheme(),
+        &options.trusted_proxies,
+    )?;
+    apply_header_operations(&mut headers, &placeholders.expand_all(&options.header_up)?);
+    for (key, value) in headers.iter() {
         buf.extend_from_slice(key.as_str().as_bytes());
         buf.extend_from_slice(b": ");
         buf.extend_from_slice(value.as_bytes());
@@ -244,35 +412,375 @@ fn request_to_bytes(request: &Request<BytesMut>) -> Result<Vec<u8>, CbltError> {
     Ok(buf)
 }
 
+/// Path and query sent to the backend: strip_prefix, then rewrite, then add_prefix
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn upstream_uri<'a>(request: &'a Request<BytesMut>, options: &ReverseProxyOptions) -> Cow<'a, str> {
+    let uri = request.uri();
+    if options.strip_prefix.is_none() && options.rewrite.is_empty() && options.add_prefix.is_none()
+    {
+        return Cow::Borrowed(uri.path_and_query().map_or("/", |pq| pq.as_str()));
+    }
+
+    let mut path = Cow::Borrowed(uri.path());
+    if let Some(prefix) = &options.strip_prefix {
+        // Whole segments only, "/api" is not stripped from "/apis"
+        match path.strip_prefix(prefix.as_str()) {
+            Some("") => path = Cow::Borrowed("/"),
+            Some(rest) if rest.starts_with('/') => path = Cow::Owned(rest.to_string()),
+            _ => {}
+        }
+    }
+    for rewrite in &options.rewrite {
+        let rewritten = rewrite.regex.replace(&path, rewrite.replacement.as_str());
+        if let Cow::Owned(rewritten) = rewritten {
+            path = Cow::Owned(rewritten);
+        }
+    }
+    let mut upstream_uri = String::with_capacity(path.len() + 16);
+    if let Some(prefix) = &options.add_prefix {
+        upstream_uri.push_str(prefix);
+    }
+    if !path.starts_with('/') {
+        upstream_uri.push('/');
+    }
+    upstream_uri.push_str(&path);
+    if let Some(query) = uri.query() {
+        upstream_uri.push('?');
+        upstream_uri.push_str(query);
+    }
+    Cow::Owned(upstream_uri)
+}
+
+/// Sets X-Forwarded-For, -Proto, -Host and Forwarded for the backend. Values sent by
+/// a trusted proxy are extended, from any other client they are replaced.
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn forwarded_headers(
+    headers: &mut HeaderMap,
+    remote: SocketAddr,
+    scheme: &str,
+    trusted_proxies: &[IpCidr],
+) -> Result<(), CbltError> {
+    let remote_ip = remote.ip().to_canonical();
+    let trusted = trusted_proxies.iter().any(|cidr| cidr.contains(&remote_ip));
+    // Several header lines of one field form a single comma separated list
+    let prior = |headers: &HeaderMap, name: &HeaderName| -> Option<String> {
+        let values: Vec<&str> = headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect();
+        (trusted && !values.is_empty()).then(|| values.join(", "))
+    };
+    let host = headers
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .map(str::to_string);
+
+    let forwarded_for = match prior(headers, &X_FORWARDED_FOR) {
+        Some(prior) => format!("{}, {}", prior, remote_ip),
+        None => remote_ip.to_string(),
+    };
+    headers.insert(X_FORWARDED_FOR, HeaderValue::from_str(&forwarded_for)?);
+    if !trusted || !headers.contains_key(X_FORWARDED_PROTO) {
+        headers.insert(X_FORWARDED_PROTO, HeaderValue::from_str(scheme)?);
+    }
+    if !trusted || !headers.contains_key(X_FORWARDED_HOST) {
+        match &host {
+            Some(host) => headers.insert(X_FORWARDED_HOST, HeaderValue::from_str(host)?),
+            None => headers.remove(X_FORWARDED_HOST),
+        };
+    }
+
+    // RFC 7239, IPv6 nodes are bracketed and quoted
+    let mut element = match remote_ip {
+        IpAddr::V4(ip) => format!("for={};proto={}", ip, scheme),
+        IpAddr::V6(ip) => format!("for=\"[{}]\";proto={}", ip, scheme),
+    };
+    if let Some(host) = host.filter(|host| !host.contains(['"', '\\'])) {
+        element.push_str(";host=\"");
+        element.push_str(&host);
+        element.push('"');
+    }
+    let forwarded = match prior(headers, &FORWARDED) {
+        Some(prior) => format!("{}, {}", prior, element),
+        None => element,
+    };
+    headers.insert(FORWARDED, HeaderValue::from_str(&forwarded)?);
+    Ok(())
+}
+
+/// Values of the placeholders in header_up and header_down
+struct Placeholders<'a> {
+    request: &'a Request<BytesMut>,
+    remote: SocketAddr,
+    upstream: &'a str, // backend as configured, e.g. "http://10.0.0.1:8080"
+    upstream_hostport: &'a str, // e.g. "10.0.0.1:8080"
+}
+
+impl Placeholders<'_> {
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    fn get(&self, name: &str) -> Option<Cow<'_, str>> {
+        let uri = self.request.uri();
+        match name {
+            "remote_ip" => Some(self.remote.ip().to_string().into()),
+            "remote_port" => Some(self.remote.port().to_string().into()),
+            "host" => self
+                .request
+                .headers()
+                .get(HOST)
+                .and_then(|host| host.to_str().ok())
+                .map(Cow::Borrowed),
+            "scheme" => Some(self.scheme().into()),
+            "method" => Some(self.request.method().as_str().into()),
+            "uri" => Some(uri.path_and_query().map_or("/", |pq| pq.as_str()).into()),
+            "path" => Some(uri.path().into()),
+            "query" => Some(uri.query().unwrap_or("").into()),
+            "upstream" => Some(self.upstream.into()),
+            "upstream_hostport" => Some(self.upstream_hostport.into()),
+            _ => None,
+        }
+    }
+
+    /// Scheme of the client connection, set by `request_process`
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    fn scheme(&self) -> &str {
+        self.request
+            .extensions()
+            .get::<Scheme>()
+            .map_or("http", |scheme| scheme.as_str())
+    }
+
+    /// Replaces known `{name}` placeholders, unknown ones are kept as they are
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    fn expand(&self, value: &HeaderValue) -> Result<HeaderValue, CbltError> {
+        let template = match value.to_str() {
+            Ok(template) if template.contains('{') => template,
+            _ => return Ok(value.clone()),
+        };
+        let mut expanded = String::with_capacity(template.len() + 32);
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let Some(len) = rest[start..].find('}') else {
+                break;
+            };
+            expanded.push_str(&rest[..start]);
+            let placeholder = &rest[start..=start + len];
+            match self.get(&placeholder[1..len]) {
+                Some(replacement) => expanded.push_str(&replacement),
+                None => expanded.push_str(placeholder),
+            }
+            rest = &rest[start + len + 1..];
+        }
+        expanded.push_str(rest);
+        Ok(HeaderValue::from_str(&expanded)?)
+    }
+
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    fn expand_all(
+        &self,
+        operations: &[HeaderOperation],
+    ) -> Result<Vec<HeaderOperation>, CbltError> {
+        operations
+            .iter()
+            .map(|operation| {
+                Ok(match operation {
+                    HeaderOperation::Set(name, value) => {
+                        HeaderOperation::Set(name.clone(), self.expand(value)?)
+                    }
+                    HeaderOperation::Add(name, value) => {
+                        HeaderOperation::Add(name.clone(), self.expand(value)?)
+                    }
+                    HeaderOperation::Remove(name) => HeaderOperation::Remove(name.clone()),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Rebuilds the response head of a backend with header operations applied
+/// Response head of the backend as the client gets it
+struct ResponseHead {
+    bytes: Vec<u8>,
+    status: StatusCode,
+    framing: ResponseFraming,
+    keep_alive: bool, // the backend connection can be reused after the body
+}
+
+/// Applies header_down and the `header` directives to a backend response head and
+/// replaces its connection headers with those of the client connection
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn response_head(
+    head: &[u8],
+    request: &Request<BytesMut>,
+    operations: &[HeaderOperation],
+) -> Result<ResponseHead, CbltError> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut res = httparse::Response::new(&mut headers);
+    res.parse(head).map_err(|e| CbltError::ResponseError {
+        details: e.to_string(),
+        status_code: StatusCode::BAD_GATEWAY,
+    })?;
+
+    let mut header_map = HeaderMap::with_capacity(res.headers.len());
+    for header in res.headers.iter() {
+        header_map.append(
+            HeaderName::from_bytes(header.name.as_bytes())?,
+            HeaderValue::from_bytes(header.value)?,
+        );
+    }
+    let status =
+        StatusCode::from_u16(res.code.unwrap_or(502)).map_err(|e| CbltError::ResponseError {
+            details: e.to_string(),
+            status_code: StatusCode::BAD_GATEWAY,
+        })?;
+    let framing = response_framing(request, status, &header_map)?;
+    let keep_alive = match res.version {
+        Some(0) => has_connection_token(&header_map, "keep-alive"),
+        _ => !has_connection_token(&header_map, "close"),
+    } && !matches!(
+        framing,
+        ResponseFraming::UntilClose | ResponseFraming::Upgrade
+    );
+
+    if framing == ResponseFraming::Upgrade {
+        // Connection and Upgrade confirm the switch to the client
+        apply_header_operations(&mut header_map, operations);
+        if let Some(ResponseHeaders(operations)) = request.extensions().get::<ResponseHeaders>() {
+            apply_header_operations(&mut header_map, operations);
+        }
+    } else {
+        remove_hop_by_hop(&mut header_map);
+        apply_header_operations(&mut header_map, operations);
+        finish_headers(&mut header_map, request);
+        if framing == ResponseFraming::UntilClose {
+            header_map.insert(CONNECTION, HeaderValue::from_static("close"));
+        }
+    }
+
+    let mut buf = Vec::with_capacity(head.len() + 64);
+    buf.extend_from_slice(b"HTTP/1.1 ");
+    let mut itoa_buf = itoa::Buffer::new();
+    buf.extend_from_slice(itoa_buf.format(status.as_u16()).as_bytes());
+    buf.extend_from_slice(b" ");
+    buf.extend_from_slice(res.reason.unwrap_or("").as_bytes());
+    buf.extend_from_slice(b"\r\n");
+    for (key, value) in header_map.iter() {
+        buf.extend_from_slice(key.as_str().as_bytes());
+        buf.extend_from_slice(b": ");
+        buf.extend_from_slice(value.as_bytes());
+        buf.extend_from_slice(b"\r\n");
+    }
+    buf.extend_from_slice(b"\r\n");
+    Ok(ResponseHead {
+        bytes: buf,
+        status,
+        framing,
+        keep_alive,
+    })
+}
+
+/// Body length of a backend response as RFC 9112 section 6.3 determines it
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn response_framing(
+    request: &Request<BytesMut>,
+    status: StatusCode,
+    headers: &HeaderMap,
+) -> Result<ResponseFraming, CbltError> {
+    if status == StatusCode::SWITCHING_PROTOCOLS {
+        return Ok(ResponseFraming::Upgrade);
+    }
+    if request.method() == Method::HEAD
+        || status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+    {
+        return Ok(ResponseFraming::Empty);
+    }
+    if let Some(transfer_encoding) = headers.get(TRANSFER_ENCODING) {
+        // Chunked must be the final coding, otherwise the body ends with the connection
+        let chunked = transfer_encoding
+            .to_str()
+            .ok()
+            .and_then(|codings| codings.rsplit(',').next())
+            .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"));
+        return Ok(if chunked {
+            ResponseFraming::Chunked
+        } else {
+            ResponseFraming::UntilClose
+        });
+    }
+    match headers.get(CONTENT_LENGTH) {
+        Some(content_length) => content_length
+            .to_str()
+            .ok()
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .map(ResponseFraming::ContentLength)
+            .ok_or(CbltError::ResponseError {
+                details: "Invalid Content-Length from backend".to_string(),
+                status_code: StatusCode::BAD_GATEWAY,
+            }),
+        None => Ok(ResponseFraming::UntilClose),
+    }
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn has_connection_token(headers: &HeaderMap, token: &str) -> bool {
+    headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
+/// Removes the headers of a single connection, including those listed in Connection
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn remove_hop_by_hop(headers: &mut HeaderMap) {
+    let listed: Vec<HeaderName> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in [CONNECTION, KEEP_ALIVE, PROXY_CONNECTION, UPGRADE] {
+        headers.remove(name);
+    }
+}
+
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
 async fn get_header_len<S>(socket: &mut S, buf: &mut BytesMut) -> Result<usize, CbltError>
 where
     S: AsyncReadExt + Unpin,
 {
     loop {
+        // The head may already be in the buffer, e.g. after an interim 1xx response
+        if !buf.is_empty() {
+            let mut headers = [httparse::EMPTY_HEADER; 64]; // Increased header capacity
+            let mut res = httparse::Response::new(&mut headers);
+
+            match res.parse(buf) {
+                Ok(httparse::Status::Complete(header_len)) => {
+                    return Ok(header_len);
+                }
+                Ok(httparse::Status::Partial) => {
+                    // Need to read more data
+                }
+                Err(e) => {
+                    return Err(CbltError::ResponseError {
+                        details: e.to_string(),
+                        status_code: StatusCode::BAD_GATEWAY,
+                    });
+                }
+            }
+        }
         let bytes_read = socket.read_buf(buf).await.unwrap_or(0);
         if bytes_read == 0 {
             break;
         }
-        // Try to parse the response
-        let mut headers = [httparse::EMPTY_HEADER; 64]; // Increased header capacity
-        let mut res = httparse::Response::new(&mut headers);
-
-        match res.parse(buf) {
-            Ok(httparse::Status::Complete(header_len)) => {
-                return Ok(header_len);
-            }
-            Ok(httparse::Status::Partial) => {
-                // Need to read more data
-                continue;
-            }
-            Err(e) => {
-                return Err(CbltError::ResponseError {
-                    details: e.to_string(),
-                    status_code: StatusCode::BAD_GATEWAY,
-                });
-            }
-        }
     }
 
     Err(CbltError::ResponseError {
@@ -281,15 +789,22 @@ where
     })
 }
 
-use crate::config::{Directive, LoadBalancePolicy, ReverseProxyOptions};
+use crate::config::{
+    Destination, Directive, HeaderOperation, HealthCheckOptions, IpCidr, LoadBalancePolicy,
+    ReverseProxyOptions, StickyCookie,
+};
+use crate::health::{health_checks, FailureWindow};
+use aws_lc_rs::{constant_time, hmac};
+use rand::seq::SliceRandom;
 use std::collections::HashMap;
 use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::net::TcpStream;
 use tokio::sync::RwLock;
-use tokio::time::timeout;
+use tokio::time::{timeout, Instant};
 
 #[derive(Debug, Clone)]
 pub enum AliveState {
@@ -297,49 +812,124 @@ pub enum AliveState {
     Dead {
         since: u64,        // timestamp when marked dead
         retries_left: u64, // retries remaining
+        cooldown: u64,     // seconds until the next retry
     },
+    Unhealthy, // failed active health checks, only a passing one brings it back
 }
 #[derive(Debug, Clone)]
 pub struct Backend {
     pub url: String,
+    pub weight: u32,
     pub alive_state: Arc<RwLock<AliveState>>,
+    pub in_flight: Arc<AtomicUsize>, // requests and tunnels being proxied
 }
 
 pub struct ReverseProxyState {
     pub backends: Vec<Backend>,
     pub lb_policy: LoadBalancePolicy,
-    pub current_backend: Arc<RwLock<usize>>, // For Round Robin
+    pub current_backend: AtomicUsize, // For Round Robin
     pub options: ReverseProxyOptions,
+    pub upstream_tls: Option<UpstreamTls>, // only with https:// backends
+    pub pool: ConnectionPool,              // idle keep-alive connections per backend
+    pub failures: Vec<FailureWindow>,      // recent failures per backend, for passive health checks
+    pub sticky: Option<StickySessions>,    // only with the cookie lb_policy
+    current_weights: Option<Mutex<Vec<i64>>>, // smooth weighted round robin, only with weights
 }
 #[derive(Debug, Clone)]
 pub struct LiveBackend {
     address: heapless::String<HEAPLESS_STRING_SIZE>,
     backend_index: usize,
+    set_cookie: bool, // the client gets a new sticky session cookie
+}
+
+/// Keys of the cookie lb_policy, the cookie value is an HMAC of the backend URL
+pub struct StickySessions {
+    pub cookie: StickyCookie,
+    tokens: Vec<String>, // cookie value per backend
+}
+
+impl StickySessions {
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    fn new(cookie: StickyCookie, backends: &[String]) -> Self {
+        let key = match &cookie.secret {
+            Some(secret) => hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()),
+            None => hmac::Key::new(hmac::HMAC_SHA256, &rand::random::<[u8; 32]>()),
+        };
+        let tokens = backends
+            .iter()
+            .map(|url| {
+                let tag = hmac::sign(&key, url.as_bytes());
+                tag.as_ref().iter().fold(String::new(), |mut hex, byte| {
+                    let _ = write!(hex, "{:02x}", byte);
+                    hex
+                })
+            })
+            .collect();
+        Self { cookie, tokens }
+    }
 }
 
 impl ReverseProxyState {
     #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
     pub fn new(
-        backends: Vec<String>,
+        destinations: Vec<Destination>,
         lb_policy: LoadBalancePolicy,
         options: ReverseProxyOptions,
     ) -> Result<Self, CbltError> {
+        let current_weights = destinations
+            .iter()
+            .any(|destination| destination.weight != 1)
+            .then(|| Mutex::new(vec![0; destinations.len()]));
+        let weights: Vec<u32> = destinations.iter().map(|d| d.weight).collect();
+        let backends: Vec<String> = destinations.into_iter().map(|d| d.url).collect();
         let now_timestamp_seconds = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)?
             .as_secs();
+        let upstream_tls = if backends.iter().any(|url| url.starts_with("https://")) {
+            Some(UpstreamTls::new(&options.tls)?)
+        } else {
+            None
+        };
+        let pool = ConnectionPool::new(backends.len(), options.keepalive.clone());
+        let failures = backends.iter().map(|_| FailureWindow::default()).collect();
+        let sticky = match &lb_policy {
+            LoadBalancePolicy::Cookie(cookie) => {
+                Some(StickySessions::new(cookie.clone(), &backends))
+            }
+            _ => None,
+        };
         Ok(Self {
             backends: backends
                 .into_iter()
-                .map(|url| Backend {
+                .zip(weights)
+                .map(|(url, weight)| Backend {
                     url,
+                    weight,
                     alive_state: Arc::new(RwLock::new(AliveState::Alive(now_timestamp_seconds))),
+                    in_flight: Arc::new(AtomicUsize::new(0)),
                 })
                 .collect(),
             lb_policy,
-            current_backend: Arc::new(RwLock::new(0)),
+            current_backend: AtomicUsize::new(0),
             options: options.clone(),
+            upstream_tls,
+            pool,
+            failures,
+            sticky,
+            current_weights,
         })
     }
+    /// Probes the backends in the background for as long as the state is in use
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn start_health_checks(&self, options: HealthCheckOptions) {
+        let backends = self
+            .backends
+            .iter()
+            .map(|backend| (backend.url.clone(), Arc::downgrade(&backend.alive_state)))
+            .collect();
+        tokio::spawn(health_checks(backends, options, self.upstream_tls.clone()));
+    }
+
     #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
     pub async fn set_dead_backend(&self, live_backend: &LiveBackend) -> Result<(), CbltError> {
         let now_timestamp_seconds = current_timestamp_seconds();
@@ -347,71 +937,64 @@ impl ReverseProxyState {
         *backend.alive_state.write().await = AliveState::Dead {
             since: now_timestamp_seconds,
             retries_left: self.options.lb_retries,
+            cooldown: self.options.lb_interval,
+        };
+        Ok(())
+    }
+
+    /// Counts a failed request of a backend and takes it out of rotation for the
+    /// cooldown once the passive health check threshold is reached
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub async fn record_failure(&self, live_backend: &LiveBackend) -> Result<(), CbltError> {
+        let Some(passive) = &self.options.passive_health else {
+            return Ok(());
         };
+        if self.failures[live_backend.backend_index].record(Instant::now(), passive) {
+            let backend = &self.backends[live_backend.backend_index];
+            info!(
+                "Backend marked dead by passive health checks: {}",
+                backend.url
+            );
+            *backend.alive_state.write().await = AliveState::Dead {
+                since: current_timestamp_seconds(),
+                retries_left: 1, // back in rotation after the cooldown
+                cooldown: passive.cooldown.as_secs(),
+            };
+        }
         Ok(())
     }
 
+    /// Whether a backend response counts as a failure for the passive health checks
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn is_failure_status(&self, status: StatusCode) -> bool {
+        self.options.passive_health.as_ref().is_some_and(|passive| {
+            passive
+                .status
+                .iter()
+                .any(|(from, to)| (*from..=*to).contains(&status.as_u16()))
+        })
+    }
+
     #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
     pub async fn set_alive_backend(&self, live_backend: &LiveBackend) -> Result<(), CbltError> {
-        let now_timestamp_seconds = current_timestamp_seconds();
         let backend = &self.backends[live_backend.backend_index];
+        // Only a change of the state takes the write lock
+        if matches!(*backend.alive_state.read().await, AliveState::Alive(_)) {
+            return Ok(());
+        }
+        let now_timestamp_seconds = current_timestamp_seconds();
         *backend.alive_state.write().await = AliveState::Alive(now_timestamp_seconds);
         Ok(())
     }
 
     #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
-    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
-    pub async fn get_next_backend(&self, addr: SocketAddr) -> Result<LiveBackend, CbltError> {
-        // Implement load balancing logic here
+    pub async fn get_next_backend(
+        &self,
+        addr: SocketAddr,
+        request: &Request<BytesMut>,
+    ) -> Result<LiveBackend, CbltError> {
         match &self.lb_policy {
-            LoadBalancePolicy::RoundRobin => {
-                let mut idx = self.current_backend.write().await;
-                let total_backends = self.backends.len();
-                for _ in 0..total_backends {
-                    let backend = &self.backends[*idx];
-                    let mut alive_state = backend.alive_state.write().await;
-                    match &mut *alive_state {
-                        AliveState::Alive(_timestamp) => {
-                            let live_backend = LiveBackend {
-                                address: heapless::String::from_str(backend.url.as_str())
-                                    .map_err(|_| CbltError::HeaplessError {})?,
-                                backend_index: *idx,
-                            };
-                            *idx = (*idx + 1) % total_backends;
-                            return Ok(live_backend);
-                        }
-                        AliveState::Dead {
-                            since,
-                            retries_left,
-                        } => {
-                            let now_timestamp_seconds = current_timestamp_seconds();
-
-                            if now_timestamp_seconds > (*since + self.options.lb_interval) {
-                                if *retries_left > 0 {
-                                    // Attempt to bring backend back to life
-                                    *retries_left -= 1;
-                                    *alive_state = AliveState::Alive(now_timestamp_seconds);
-                                    let live_backend = LiveBackend {
-                                        address: heapless::String::from_str(backend.url.as_str())
-                                            .map_err(|_| CbltError::HeaplessError {})?,
-                                        backend_index: *idx,
-                                    };
-                                    *idx = (*idx + 1) % total_backends;
-                                    return Ok(live_backend);
-                                } else {
-                                    // Keep backend dead
-                                    *since = now_timestamp_seconds; // Reset dead since timestamp
-                                }
-                            }
-                            *idx = (*idx + 1) % total_backends;
-                        }
-                    }
-                }
-                Err(CbltError::ResponseError {
-                    details: "No healthy backends".to_string(),
-                    status_code: StatusCode::BAD_GATEWAY,
-                })
-            }
+            LoadBalancePolicy::RoundRobin => self.round_robin().await,
             LoadBalancePolicy::IPHash => {
                 let addr_octets = match addr.ip() {
                     IpAddr::V4(addr) => addr.octets(),
@@ -422,54 +1005,293 @@ impl ReverseProxyState {
                         });
                     }
                 };
+                let total_weight = self.backends.iter().map(|b| b.weight).sum();
                 let mut backend_idx =
-                    generate_number_from_octet(addr_octets, self.backends.len() as u32);
+                    self.weighted_index(generate_number_from_octet(addr_octets, total_weight));
                 let total_backends = self.backends.len();
                 for _ in 0..total_backends {
-                    let backend = &self.backends[backend_idx as usize];
-                    let mut alive_state = backend.alive_state.write().await;
-                    match &mut *alive_state {
-                        AliveState::Alive(_timestamp) => {
-                            return Ok(LiveBackend {
-                                address: heapless::String::from_str(backend.url.as_str())
-                                    .map_err(|_| CbltError::HeaplessError {})?,
-                                backend_index: backend_idx as usize,
-                            });
-                        }
-                        AliveState::Dead {
-                            since,
-                            retries_left,
-                        } => {
-                            let now_timestamp_seconds = current_timestamp_seconds();
-                            if now_timestamp_seconds > (*since + self.options.lb_interval) {
-                                if *retries_left > 0 {
-                                    // Attempt to bring backend back to life
-                                    *retries_left -= 1;
-                                    *alive_state = AliveState::Alive(now_timestamp_seconds);
-                                    return Ok(LiveBackend {
-                                        address: heapless::String::from_str(backend.url.as_str())
-                                            .map_err(|_| CbltError::HeaplessError {})?,
-                                        backend_index: backend_idx as usize,
-                                    });
-                                } else {
-                                    // Keep backend dead
-                                    *since = now_timestamp_seconds; // Reset dead since timestamp
-                                }
-                            }
-                            backend_idx = (backend_idx + 1) % total_backends as u32;
-                        }
+                    if self.is_available(backend_idx).await {
+                        return self.live_backend(backend_idx);
                     }
+                    backend_idx = (backend_idx + 1) % total_backends;
                 }
-                Err(CbltError::ResponseError {
-                    details: "No healthy backends".to_string(),
-                    status_code: StatusCode::BAD_GATEWAY,
-                })
+                Err(no_healthy_backends())
+            }
+            LoadBalancePolicy::LeastConn => {
+                // Shuffled first, so the stable sort breaks ties at random
+                let mut candidates: Vec<usize> = (0..self.backends.len()).collect();
+                candidates.shuffle(&mut rand::thread_rng());
+                candidates.sort_by(|&a, &b| self.compare_load(a, b));
+                for backend_idx in candidates {
+                    if self.is_available(backend_idx).await {
+                        return self.live_backend(backend_idx);
+                    }
+                }
+                Err(no_healthy_backends())
+            }
+            LoadBalancePolicy::Random => {
+                let mut candidates = self.candidates().await;
+                while let Some(picked) = self.random_candidate(&candidates) {
+                    if self.is_available(picked).await {
+                        return self.live_backend(picked);
+                    }
+                    candidates.retain(|&idx| idx != picked);
+                }
+                Err(no_healthy_backends())
+            }
+            LoadBalancePolicy::RandomChoose(choices) => {
+                // Power of choices: the least loaded of a few random backends, no shared
+                // counter and no scan of all loads
+                let mut candidates = self.candidates().await;
+                while !candidates.is_empty() {
+                    let picked = candidates
+                        .choose_multiple(&mut rand::thread_rng(), *choices)
+                        .copied()
+                        .min_by(|&a, &b| self.compare_load(a, b))
+                        .ok_or_else(no_healthy_backends)?;
+                    if self.is_available(picked).await {
+                        return self.live_backend(picked);
+                    }
+                    candidates.retain(|&idx| idx != picked);
+                }
+                Err(no_healthy_backends())
+            }
+            LoadBalancePolicy::Cookie(_) => {
+                if let Some(backend_idx) = self.sticky_backend(request) {
+                    if self.is_available(backend_idx).await {
+                        return self.live_backend(backend_idx);
+                    }
+                }
+                // A new client, or its backend is down: pick another one and tell the client
+                let mut live_backend = self.round_robin().await?;
+                live_backend.set_cookie = true;
+                Ok(live_backend)
+            }
+        }
+    }
+
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    async fn round_robin(&self) -> Result<LiveBackend, CbltError> {
+        if let Some(current_weights) = &self.current_weights {
+            return self.weighted_round_robin(current_weights).await;
+        }
+        let total_backends = self.backends.len();
+        let start = self.current_backend.fetch_add(1, Ordering::Relaxed);
+        for offset in 0..total_backends {
+            let backend_idx = start.wrapping_add(offset) % total_backends;
+            if self.is_available(backend_idx).await {
+                return self.live_backend(backend_idx);
+            }
+        }
+        Err(no_healthy_backends())
+    }
+
+    /// Backends that may take a request
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    async fn candidates(&self) -> Vec<usize> {
+        let mut candidates = Vec::with_capacity(self.backends.len());
+        for backend_idx in 0..self.backends.len() {
+            if self.may_be_available(backend_idx).await {
+                candidates.push(backend_idx);
+            }
+        }
+        candidates
+    }
+
+    /// Random candidate, backends with a higher weight are proportionally more likely
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    fn random_candidate(&self, candidates: &[usize]) -> Option<usize> {
+        candidates
+            .choose_weighted(&mut rand::thread_rng(), |&idx| self.backends[idx].weight)
+            .ok()
+            .copied()
+    }
+
+    /// Orders backends by requests in flight relative to their weight
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    fn compare_load(&self, a: usize, b: usize) -> std::cmp::Ordering {
+        // a/wa < b/wb without division
+        let load = |idx: usize| self.backends[idx].in_flight.load(Ordering::Relaxed) as u64;
+        (load(a) * self.backends[b].weight as u64).cmp(&(load(b) * self.backends[a].weight as u64))
+    }
+
+    /// Smooth weighted round robin as nginx does it, the picks of a heavier backend are
+    /// spread between those of the others instead of coming in a row
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    async fn weighted_round_robin(
+        &self,
+        current_weights: &Mutex<Vec<i64>>,
+    ) -> Result<LiveBackend, CbltError> {
+        let mut candidates = self.candidates().await;
+        while !candidates.is_empty() {
+            let picked = {
+                let mut current = current_weights.lock().map_err(|_| no_healthy_backends())?;
+                let mut total = 0;
+                for &idx in &candidates {
+                    current[idx] += self.backends[idx].weight as i64;
+                    total += self.backends[idx].weight as i64;
+                }
+                let mut picked = candidates[0];
+                for &idx in &candidates {
+                    if current[idx] > current[picked] {
+                        picked = idx;
+                    }
+                }
+                current[picked] -= total;
+                picked
+            };
+            // The state may have changed meanwhile
+            if self.is_available(picked).await {
+                return self.live_backend(picked);
+            }
+            candidates.retain(|&idx| idx != picked);
+        }
+        Err(no_healthy_backends())
+    }
+
+    /// Backend of a slot in 0..total weight, each backend has as many slots as its weight
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    fn weighted_index(&self, mut slot: u32) -> usize {
+        for (idx, backend) in self.backends.iter().enumerate() {
+            if slot < backend.weight {
+                return idx;
+            }
+            slot -= backend.weight;
+        }
+        0
+    }
+
+    /// Backend named by a valid sticky session cookie of the request
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    fn sticky_backend(&self, request: &Request<BytesMut>) -> Option<usize> {
+        let sticky = self.sticky.as_ref()?;
+        let value = request
+            .headers()
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(name, _)| *name == sticky.cookie.name)
+            .map(|(_, value)| value.trim_matches('"'))?;
+        // Constant time, so valid values cannot be guessed byte by byte
+        sticky.tokens.iter().position(|token| {
+            constant_time::verify_slices_are_equal(token.as_bytes(), value.as_bytes()).is_ok()
+        })
+    }
+
+    /// Set-Cookie value that sends the client to the same backend next time
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn sticky_cookie(
+        &self,
+        live_backend: &LiveBackend,
+        secure: bool,
+    ) -> Result<Option<HeaderValue>, CbltError> {
+        let Some(sticky) = &self.sticky else {
+            return Ok(None);
+        };
+        let cookie = &sticky.cookie;
+        let mut value = format!(
+            "{}={}; Path={}",
+            cookie.name, sticky.tokens[live_backend.backend_index], cookie.path
+        );
+        if let Some(max_age) = cookie.max_age {
+            value.push_str("; Max-Age=");
+            value.push_str(&max_age.to_string());
+        }
+        value.push_str("; HttpOnly; SameSite=Lax");
+        if secure {
+            value.push_str("; Secure");
+        }
+        Ok(Some(HeaderValue::from_str(&value)?))
+    }
+
+    /// Whether a backend can take a request. A dead one is brought back after its
+    /// cooldown while it has retries left.
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    async fn is_available(&self, backend_idx: usize) -> bool {
+        // Most of the time the state is read only, without blocking other requests
+        match &*self.backends[backend_idx].alive_state.read().await {
+            AliveState::Alive(_timestamp) => return true,
+            AliveState::Unhealthy => return false,
+            AliveState::Dead { .. } => {}
+        }
+        let mut alive_state = self.backends[backend_idx].alive_state.write().await;
+        match &mut *alive_state {
+            AliveState::Alive(_timestamp) => true,
+            AliveState::Dead {
+                since,
+                retries_left,
+                cooldown,
+            } => {
+                let now_timestamp_seconds = current_timestamp_seconds();
+                if now_timestamp_seconds > (*since + *cooldown) {
+                    if *retries_left > 0 {
+                        // Attempt to bring backend back to life
+                        *retries_left -= 1;
+                        *alive_state = AliveState::Alive(now_timestamp_seconds);
+                        return true;
+                    }
+                    // Keep backend dead
+                    *since = now_timestamp_seconds; // Reset dead since timestamp
+                }
+                false
             }
+            AliveState::Unhealthy => false,
+        }
+    }
+
+    /// Like `is_available`, but without bringing a dead backend back
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    async fn may_be_available(&self, backend_idx: usize) -> bool {
+        match &*self.backends[backend_idx].alive_state.read().await {
+            AliveState::Alive(_timestamp) => true,
+            AliveState::Dead {
+                since,
+                retries_left,
+                cooldown,
+            } => *retries_left > 0 && current_timestamp_seconds() > *since + *cooldown,
+            AliveState::Unhealthy => false,
         }
     }
+
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    fn live_backend(&self, backend_idx: usize) -> Result<LiveBackend, CbltError> {
+        Ok(LiveBackend {
+            address: heapless::String::from_str(self.backends[backend_idx].url.as_str())
+                .map_err(|_| CbltError::HeaplessError {})?,
+            backend_index: backend_idx,
+            set_cookie: false,
+        })
+    }
+
+    /// Counts the request as in flight to the backend until the guard is dropped
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn start_request(&self, live_backend: &LiveBackend) -> InFlight {
+        let in_flight = self.backends[live_backend.backend_index].in_flight.clone();
+        in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(in_flight)
+    }
 }
 
-fn current_timestamp_seconds() -> u64 {
+/// In-flight request or tunnel of a backend, for the least_conn policy
+pub struct InFlight(Arc<AtomicUsize>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn no_healthy_backends() -> CbltError {
+    CbltError::ResponseError {
+        details: "No healthy backends".to_string(),
+        status_code: StatusCode::BAD_GATEWAY,
+    }
+}
+
+pub fn current_timestamp_seconds() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap_or(Duration::from_secs(0))
@@ -487,3 +1309,456 @@ fn generate_number_from_octet(octets: [u8; 4], max: u32) -> u32 {
 
     (hash % max as u64) as u32
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{
+        Destination, HeaderOperation, IpCidr, LoadBalancePolicy, PathRewrite, ReverseProxyOptions,
+        StickyCookie,
+    };
+    use crate::reverse_proxy::{
+        forwarded_headers, request_to_bytes, response_framing, response_head, upstream_uri,
+        AliveState, Placeholders, ReverseProxyState,
+    };
+    use crate::upstream::ResponseFraming;
+    use bytes::BytesMut;
+    use http::uri::Scheme;
+    use http::{HeaderMap, HeaderName, HeaderValue, Request, StatusCode};
+    use regex::Regex;
+    use std::error::Error;
+
+    #[test]
+    fn test_header_up() -> Result<(), Box<dyn Error>> {
+        let mut request = Request::builder()
+            .uri("/api/items?page=2")
+            .header("Host", "example.com")
+            .header("X-Debug", "1")
+            .body(BytesMut::new())?;
+        request.extensions_mut().insert(Scheme::HTTPS);
+        let placeholders = Placeholders {
+            request: &request,
+            remote: "10.1.2.3:54321".parse()?,
+            upstream: "http://backend:8080",
+            upstream_hostport: "backend:8080",
+        };
+        let header_up = vec![
+            HeaderOperation::Set(
+                HeaderName::from_static("host"),
+                HeaderValue::from_static("{upstream_hostport}"),
+            ),
+            HeaderOperation::Set(
+                HeaderName::from_static("x-real-ip"),
+                HeaderValue::from_static("{remote_ip}"),
+            ),
+            HeaderOperation::Add(
+                HeaderName::from_static("x-original"),
+                HeaderValue::from_static("{scheme}://{host}{uri} {unknown} {"),
+            ),
+            HeaderOperation::Remove(HeaderName::from_static("x-debug")),
+        ];
+        let options = ReverseProxyOptions {
+            header_up,
+            ..ReverseProxyOptions::default()
+        };
+        let bytes = String::from_utf8(request_to_bytes(
+            &request,
+            "/api/items?page=2",
+            &options,
+            &placeholders,
+        )?)?;
+        assert!(bytes.starts_with("GET /api/items?page=2 HTTP/1.1\r\n"));
+        assert!(bytes.contains("host: backend:8080\r\n"));
+        assert!(bytes.contains("x-real-ip: 10.1.2.3\r\n"));
+        assert!(bytes.contains("x-original: https://example.com/api/items?page=2 {unknown} {\r\n"));
+        assert!(bytes.contains("x-forwarded-for: 10.1.2.3\r\n"));
+        assert!(bytes.contains("x-forwarded-proto: https\r\n"));
+        assert!(!bytes.contains("x-debug"));
+        assert!(!bytes.contains("\r\nhost: example.com\r\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_forwarded_headers() -> Result<(), Box<dyn Error>> {
+        let incoming = || -> Result<HeaderMap, Box<dyn Error>> {
+            let mut headers = HeaderMap::new();
+            headers.insert("host", HeaderValue::from_static("example.com"));
+            headers.insert("x-forwarded-for", HeaderValue::from_static("203.0.113.9"));
+            headers.insert("x-forwarded-proto", HeaderValue::from_static("https"));
+            headers.insert("forwarded", HeaderValue::from_static("for=203.0.113.9"));
+            Ok(headers)
+        };
+        let trusted = vec![IpCidr::parse("10.0.0.0/8")?];
+
+        // Spoofed values of an untrusted client are replaced
+        let mut headers = incoming()?;
+        forwarded_headers(&mut headers, "198.51.100.1:4000".parse()?, "http", &trusted)?;
+        assert_eq!(headers["x-forwarded-for"], "198.51.100.1");
+        assert_eq!(headers["x-forwarded-proto"], "http");
+        assert_eq!(headers["x-forwarded-host"], "example.com");
+        assert_eq!(
+            headers["forwarded"],
+            "for=198.51.100.1;proto=http;host=\"example.com\""
+        );
+
+        // A trusted proxy extends the chain and keeps the original scheme
+        let mut headers = incoming()?;
+        forwarded_headers(&mut headers, "10.0.0.2:4000".parse()?, "http", &trusted)?;
+        assert_eq!(headers["x-forwarded-for"], "203.0.113.9, 10.0.0.2");
+        assert_eq!(headers["x-forwarded-proto"], "https");
+        assert_eq!(
+            headers["forwarded"],
+            "for=203.0.113.9, for=10.0.0.2;proto=http;host=\"example.com\""
+        );
+
+        let mut headers = HeaderMap::new();
+        forwarded_headers(&mut headers, "[2001:db8::1]:4000".parse()?, "https", &[])?;
+        assert_eq!(headers["forwarded"], "for=\"[2001:db8::1]\";proto=https");
+        assert!(!headers.contains_key("x-forwarded-host"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_response_head() -> Result<(), Box<dyn Error>> {
+        let request = Request::builder().body(BytesMut::new())?;
+        let head =
+            b"HTTP/1.1 404 Not Found\r\nServer: nginx\r\nVary: Accept\r\nContent-Length: 0\r\n\
+                     Connection: keep-alive, X-Hop\r\nX-Hop: 1\r\nKeep-Alive: timeout=5\r\n\r\n";
+        let operations = vec![
+            HeaderOperation::Remove(HeaderName::from_static("server")),
+            HeaderOperation::Add(
+                HeaderName::from_static("vary"),
+                HeaderValue::from_static("Origin"),
+            ),
+            HeaderOperation::Set(
+                HeaderName::from_static("x-frame-options"),
+                HeaderValue::from_static("DENY"),
+            ),
+        ];
+        let response = response_head(head, &request, &operations)?;
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+        assert_eq!(response.framing, ResponseFraming::ContentLength(0));
+        assert!(response.keep_alive);
+        let rewritten = String::from_utf8(response.bytes)?;
+        assert!(rewritten.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(rewritten.ends_with("\r\n\r\n"));
+        assert!(!rewritten.contains("server:"));
+        assert!(rewritten.contains("vary: Accept\r\nvary: Origin\r\n"));
+        assert!(rewritten.contains("content-length: 0\r\n"));
+        assert!(rewritten.contains("x-frame-options: DENY\r\n"));
+        assert!(!rewritten.contains("connection:"));
+        assert!(!rewritten.contains("x-hop:"));
+        assert!(!rewritten.contains("keep-alive:"));
+
+        // A body without length ends with both connections
+        let head = b"HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\n\r\n";
+        let response = response_head(head, &request, &[])?;
+        assert_eq!(response.framing, ResponseFraming::UntilClose);
+        assert!(!response.keep_alive);
+        assert!(String::from_utf8(response.bytes)?.contains("connection: close\r\n"));
+
+        let head = b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n";
+        let response = response_head(head, &request, &[])?;
+        assert_eq!(response.framing, ResponseFraming::Upgrade);
+        assert!(String::from_utf8(response.bytes)?.contains("upgrade: websocket\r\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_response_framing() -> Result<(), Box<dyn Error>> {
+        let framing = |method: &str, status: u16, headers: &[(&str, &str)]| {
+            let request = Request::builder().method(method).body(BytesMut::new())?;
+            let mut header_map = HeaderMap::new();
+            for (name, value) in headers {
+                header_map.append(HeaderName::from_bytes(name.as_bytes())?, value.parse()?);
+            }
+            Ok::<_, Box<dyn Error>>(response_framing(
+                &request,
+                StatusCode::from_u16(status)?,
+                &header_map,
+            ))
+        };
+        let chunked = [
+            ("transfer-encoding", "gzip, chunked"),
+            ("content-length", "5"),
+        ];
+        assert_eq!(framing("GET", 200, &chunked)??, ResponseFraming::Chunked);
+        assert_eq!(framing("HEAD", 200, &chunked)??, ResponseFraming::Empty);
+        assert_eq!(framing("GET", 304, &[])??, ResponseFraming::Empty);
+        assert_eq!(framing("GET", 204, &[])??, ResponseFraming::Empty);
+        assert_eq!(
+            framing("GET", 200, &[("content-length", "42")])??,
+            ResponseFraming::ContentLength(42)
+        );
+        assert_eq!(
+            framing("GET", 200, &[("transfer-encoding", "gzip")])??,
+            ResponseFraming::UntilClose
+        );
+        assert_eq!(framing("GET", 200, &[])??, ResponseFraming::UntilClose);
+        assert!(framing("GET", 200, &[("content-length", "x")])?.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_upstream_uri() -> Result<(), Box<dyn Error>> {
+        let uri = |uri: &str, options: &ReverseProxyOptions| -> Result<String, Box<dyn Error>> {
+            let request = Request::builder().uri(uri).body(BytesMut::new())?;
+            Ok(upstream_uri(&request, options).into_owned())
+        };
+
+        let options = ReverseProxyOptions::default();
+        assert_eq!(uri("/api/users?page=2", &options)?, "/api/users?page=2");
+
+        let options = ReverseProxyOptions {
+            strip_prefix: Some("/api".to_string()),
+            ..ReverseProxyOptions::default()
+        };
+        assert_eq!(uri("/api/users?page=2", &options)?, "/users?page=2");
+        assert_eq!(uri("/api", &options)?, "/");
+        assert_eq!(uri("/api?x=1", &options)?, "/?x=1");
+        assert_eq!(uri("/apis/users", &options)?, "/apis/users");
+
+        let options = ReverseProxyOptions {
+            strip_prefix: Some("/api".to_string()),
+            add_prefix: Some("/v2".to_string()),
+            rewrite: vec![PathRewrite {
+                regex: Regex::new("^/users/([0-9]+)$")?,
+                replacement: "/people/$1".to_string(),
+            }],
+            ..ReverseProxyOptions::default()
+        };
+        assert_eq!(
+            uri("/api/users/42?full=1", &options)?,
+            "/v2/people/42?full=1"
+        );
+        assert_eq!(uri("/api/orders", &options)?, "/v2/orders");
+
+        let options = ReverseProxyOptions {
+            rewrite: vec![PathRewrite {
+                regex: Regex::new("^/old")?,
+                replacement: "".to_string(),
+            }],
+            ..ReverseProxyOptions::default()
+        };
+        assert_eq!(uri("/old", &options)?, "/");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_least_conn() -> Result<(), Box<dyn Error>> {
+        let state = ReverseProxyState::new(
+            vec![
+                Destination::new("http://a"),
+                Destination::new("http://b"),
+                Destination::new("http://c"),
+            ],
+            LoadBalancePolicy::LeastConn,
+            ReverseProxyOptions::default(),
+        )?;
+        let addr = "127.0.0.1:1234".parse()?;
+        let request = Request::builder().uri("/").body(BytesMut::new())?;
+        let next =
+            || async { Ok::<_, Box<dyn Error>>(state.get_next_backend(addr, &request).await?) };
+
+        // Ties are broken at random, every idle backend gets picked
+        let mut picked = [false; 3];
+        for _ in 0..100 {
+            picked[next().await?.backend_index] = true;
+        }
+        assert_eq!(picked, [true; 3]);
+
+        let a = state.start_request(&state.live_backend(0)?);
+        let _b = state.start_request(&state.live_backend(1)?);
+        let b2 = state.start_request(&state.live_backend(1)?);
+        assert_eq!(next().await?.backend_index, 2);
+        let _c = state.start_request(&state.live_backend(2)?);
+        let _c2 = state.start_request(&state.live_backend(2)?);
+        assert_eq!(next().await?.backend_index, 0);
+
+        // Finished requests no longer count, unhealthy backends are skipped
+        drop(a);
+        drop(b2);
+        *state.backends[0].alive_state.write().await = AliveState::Unhealthy;
+        assert_eq!(next().await?.backend_index, 1);
+        assert_eq!(
+            state.backends[0]
+                .in_flight
+                .load(std::sync::atomic::Ordering::Relaxed),
+            0
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sticky_cookie() -> Result<(), Box<dyn Error>> {
+        let cookie = StickyCookie {
+            name: "lb".to_string(),
+            max_age: Some(3600),
+            secret: Some("secret".to_string()),
+            ..StickyCookie::default()
+        };
+        let backends = vec![Destination::new("http://a"), Destination::new("http://b")];
+        let state = ReverseProxyState::new(
+            backends.clone(),
+            LoadBalancePolicy::Cookie(cookie.clone()),
+            ReverseProxyOptions::default(),
+        )?;
+        let addr = "127.0.0.1:1234".parse()?;
+        let request = |cookie: &str| {
+            Request::builder()
+                .uri("/")
+                .header("Cookie", cookie)
+                .body(BytesMut::new())
+        };
+
+        // A new client gets a cookie for the backend chosen by round robin
+        let first = state.get_next_backend(addr, &request("other=1")?).await?;
+        assert!(first.set_cookie);
+        assert_eq!(first.backend_index, 0);
+        let set_cookie = state
+            .sticky_cookie(&first, true)?
+            .ok_or("no cookie")?
+            .to_str()?
+            .to_string();
+        assert!(set_cookie.starts_with("lb="));
+        assert!(set_cookie.ends_with("; Path=/; Max-Age=3600; HttpOnly; SameSite=Lax; Secure"));
+        let value = set_cookie.split(';').next().ok_or("no value")?;
+
+        // It sticks to that backend, across restarts with the same secret
+        for _ in 0..3 {
+            let next = state
+                .get_next_backend(addr, &request(&format!("a=1; {}", value))?)
+                .await?;
+            assert_eq!((next.backend_index, next.set_cookie), (0, false));
+        }
+        let restarted = ReverseProxyState::new(
+            backends.clone(),
+            LoadBalancePolicy::Cookie(cookie),
+            ReverseProxyOptions::default(),
+        )?;
+        let next = restarted.get_next_backend(addr, &request(value)?).await?;
+        assert_eq!((next.backend_index, next.set_cookie), (0, false));
+
+        // Forged values and dead backends lead to a new cookie
+        let forged = state.get_next_backend(addr, &request("lb=1")?).await?;
+        assert!(forged.set_cookie);
+        *state.backends[0].alive_state.write().await = AliveState::Unhealthy;
+        let next = state.get_next_backend(addr, &request(value)?).await?;
+        assert_eq!((next.backend_index, next.set_cookie), (1, true));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_weighted_backends() -> Result<(), Box<dyn Error>> {
+        let destinations = vec![
+            Destination {
+                url: "http://big".to_string(),
+                weight: 5,
+            },
+            Destination::new("http://small1"),
+            Destination::new("http://small2"),
+        ];
+        let request = Request::builder().uri("/").body(BytesMut::new())?;
+        let state = ReverseProxyState::new(
+            destinations.clone(),
+            LoadBalancePolicy::RoundRobin,
+            ReverseProxyOptions::default(),
+        )?;
+        let addr = "127.0.0.1:1234".parse()?;
+        let mut picks = Vec::new();
+        for _ in 0..14 {
+            picks.push(state.get_next_backend(addr, &request).await?.backend_index);
+        }
+        // Smooth: the heavy backend is not picked five times in a row
+        assert_eq!(picks, [0, 0, 1, 0, 2, 0, 0, 0, 0, 1, 0, 2, 0, 0]);
+
+        *state.backends[0].alive_state.write().await = AliveState::Unhealthy;
+        for _ in 0..4 {
+            assert_ne!(
+                state.get_next_backend(addr, &request).await?.backend_index,
+                0
+            );
+        }
+
+        // Hashing gives each backend as many slots as its weight
+        let state = ReverseProxyState::new(
+            destinations,
+            LoadBalancePolicy::IPHash,
+            ReverseProxyOptions::default(),
+        )?;
+        let slots: Vec<usize> = (0..7).map(|slot| state.weighted_index(slot)).collect();
+        assert_eq!(slots, [0, 0, 0, 0, 0, 1, 2]);
+        let mut counts = [0; 3];
+        for i in 0..=255u8 {
+            let addr = format!("10.0.{}.{}:80", i / 16, i).parse()?;
+            counts[state.get_next_backend(addr, &request).await?.backend_index] += 1;
+        }
+        assert!(counts[0] > counts[1] * 3 && counts[0] > counts[2] * 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_random_policies() -> Result<(), Box<dyn Error>> {
+        let destinations = vec![
+            Destination::new("http://a"),
+            Destination::new("http://b"),
+            Destination::new("http://c"),
+        ];
+        let request = Request::builder().uri("/").body(BytesMut::new())?;
+        let addr = "127.0.0.1:1234".parse()?;
+
+        let state = ReverseProxyState::new(
+            destinations.clone(),
+            LoadBalancePolicy::Random,
+            ReverseProxyOptions::default(),
+        )?;
+        let mut picked = [false; 3];
+        for _ in 0..100 {
+            picked[state.get_next_backend(addr, &request).await?.backend_index] = true;
+        }
+        assert_eq!(picked, [true; 3]);
+        *state.backends[1].alive_state.write().await = AliveState::Unhealthy;
+        for _ in 0..20 {
+            assert_ne!(
+                state.get_next_backend(addr, &request).await?.backend_index,
+                1
+            );
+        }
+
+        // Of two distinct random backends the most loaded one never wins
+        let state = ReverseProxyState::new(
+            destinations.clone(),
+            LoadBalancePolicy::RandomChoose(2),
+            ReverseProxyOptions::default(),
+        )?;
+        let _a = state.start_request(&state.live_backend(0)?);
+        let _a2 = state.start_request(&state.live_backend(0)?);
+        let _b = state.start_request(&state.live_backend(1)?);
+        let mut picks = [0; 3];
+        for _ in 0..100 {
+            picks[state.get_next_backend(addr, &request).await?.backend_index] += 1;
+        }
+        assert_eq!(picks[0], 0);
+        assert!(picks[2] > picks[1]);
+
+        // Choosing among all is least_conn
+        let state = ReverseProxyState::new(
+            destinations,
+            LoadBalancePolicy::RandomChoose(5),
+            ReverseProxyOptions::default(),
+        )?;
+        let _a = state.start_request(&state.live_backend(0)?);
+        let _c = state.start_request(&state.live_backend(2)?);
+        for _ in 0..10 {
+            assert_eq!(
+                state.get_next_backend(addr, &request).await?.backend_index,
+                1
+            );
+        }
+        *state.backends[1].alive_state.write().await = AliveState::Unhealthy;
+        assert_ne!(
+            state.get_next_backend(addr, &request).await?.backend_index,
+            1
+        );
+        Ok(())
+    }
+}
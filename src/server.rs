@@ -1,16 +1,20 @@
-use crate::config::{Directive, LoadBalancePolicy};
+use crate::config::{Directive, LoadBalancePolicy, RequestLimits};
 use crate::directive::directive_process;
 use crate::error::CbltError;
+use crate::http2::h2_process;
 use std::collections::HashMap;
 
 use crate::reverse_proxy::ReverseProxyState;
+use crate::timeout::WriteTimeout;
 use log::{error, info};
 use rustls::pki_types::pem::PemObject;
 use rustls::pki_types::{CertificateDer, PrivateKeyDer};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
 use tokio::sync::{Notify, RwLock, Semaphore};
+use tokio::time::timeout;
 use tokio_rustls::TlsAcceptor;
 #[cfg(feature = "trace")]
 use tracing::instrument;
@@ -21,6 +25,17 @@ pub struct Server {
     pub hosts: HashMap<String, Vec<Directive>>, // Host -> Directives
     pub cert: Option<String>,
     pub key: Option<String>,
+    pub protocols: Vec<String>, // "h1", "h2"
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectionOptions {
+    pub keep_alive_timeout: Duration,
+    pub header_timeout: Duration,
+    pub body_timeout: Duration,
+    pub write_timeout: Duration,
+    pub max_requests: usize,
+    pub limits: RequestLimits,
 }
 
 pub struct ServerWorker {
@@ -55,20 +70,51 @@ pub struct ServerSettings {
 pub struct HostDetails {
     pub directives: Vec<Directive>,
     pub reverse_proxy_states: HashMap<String, ReverseProxyState>,
+    pub error_handlers: Vec<HostDetails>, // directives of each handle_errors block, in order
+}
+
+impl HostDetails {
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    async fn new(directives: Vec<Directive>) -> Result<Self, CbltError> {
+        let mut error_handlers = Vec::new();
+        for directive in &directives {
+            if let Directive::HandleErrors { options } = directive {
+                // handle_errors blocks do not nest, so their own handler list stays empty
+                error_handlers.push(HostDetails {
+                    reverse_proxy_states: init_proxy_states(&options.directives).await?,
+                    directives: options.directives.clone(),
+                    error_handlers: Vec::new(),
+                });
+            }
+        }
+        Ok(HostDetails {
+            reverse_proxy_states: init_proxy_states(&directives).await?,
+            directives,
+            error_handlers,
+        })
+    }
 }
 
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
 fn tls_acceptor_builder(
     cert_path: Option<&str>,
     key_path: Option<&str>,
+    protocols: &[String],
 ) -> Result<Option<TlsAcceptor>, CbltError> {
     if let (Some(cert_path), Some(key_path)) = (cert_path, key_path) {
         let certs = CertificateDer::pem_file_iter(cert_path)?.collect::<Result<Vec<_>, _>>()?;
         let key = PrivateKeyDer::from_pem_file(key_path)?;
 
-        let server_config = rustls::ServerConfig::builder()
+        let mut server_config = rustls::ServerConfig::builder()
             .with_no_client_auth()
             .with_single_cert(certs, key)?;
+        // ALPN in order of preference
+        if protocols.iter().any(|p| p == "h2") {
+            server_config.alpn_protocols.push(b"h2".to_vec());
+        }
+        if protocols.iter().any(|p| p == "h1") {
+            server_config.alpn_protocols.push(b"http/1.1".to_vec());
+        }
         Ok(Some(TlsAcceptor::from(Arc::new(server_config))))
     } else {
         Ok(None)
@@ -78,17 +124,15 @@ fn tls_acceptor_builder(
 impl ServerWorker {
     #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
     pub async fn new(server: Server) -> Result<Self, CbltError> {
-        let tls_acceptor = tls_acceptor_builder(server.cert.as_deref(), server.key.as_deref())?;
+        let tls_acceptor = tls_acceptor_builder(
+            server.cert.as_deref(),
+            server.key.as_deref(),
+            &server.protocols,
+        )?;
 
         let mut host_details: HashMap<String, HostDetails> = HashMap::new();
         for (k, v) in server.hosts {
-            host_details.insert(
-                k.to_string(),
-                HostDetails {
-                    reverse_proxy_states: init_proxy_states(&v).await?,
-                    directives: v,
-                },
-            );
+            host_details.insert(k.to_string(), HostDetails::new(v).await?);
         }
 
         Ok(ServerWorker {
@@ -108,15 +152,26 @@ impl ServerWorker {
     }
 
     #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
-    pub async fn run(&self, max_connections: usize) -> Result<(), CbltError> {
+    pub async fn run(
+        &self,
+        max_connections: usize,
+        connection_options: ConnectionOptions,
+    ) -> Result<(), CbltError> {
         let port = self.port;
         let settings = self.lock.clone();
         let is_running = self.is_running.clone();
         let notify_stop = self.notify_stop.clone();
 
         tokio::spawn(async move {
-            if let Err(err) =
-                init_server(port, settings, max_connections, is_running, notify_stop).await
+            if let Err(err) = init_server(
+                port,
+                settings,
+                max_connections,
+                connection_options,
+                is_running,
+                notify_stop,
+            )
+            .await
             {
                 error!("Error: {}", err);
             }
@@ -130,19 +185,14 @@ impl ServerWorker {
         hosts: HashMap<String, Vec<Directive>>,
         cert_path: Option<String>,
         key_path: Option<String>,
+        protocols: Vec<String>,
     ) -> Result<(), CbltError> {
         let cert_path_opt = cert_path.as_deref();
         let key_path_opt = key_path.as_deref();
-        let tls_acceptor = tls_acceptor_builder(cert_path_opt, key_path_opt)?;
+        let tls_acceptor = tls_acceptor_builder(cert_path_opt, key_path_opt, &protocols)?;
         let mut host_details: HashMap<String, HostDetails> = HashMap::new();
         for (k, v) in hosts {
-            host_details.insert(
-                k.to_string(),
-                HostDetails {
-                    reverse_proxy_states: init_proxy_states(&v).await?,
-                    directives: v,
-                },
-            );
+            host_details.insert(k.to_string(), HostDetails::new(v).await?);
         }
 
         self.lock
@@ -176,28 +226,12 @@ async fn init_proxy_states(
                         .lb_policy
                         .clone()
                         .unwrap_or(LoadBalancePolicy::RoundRobin),
-                    options.clone(),
+                    options.as_ref().clone(),
                 )?;
 
-                // if let Some(health_uri) = &options.lb_retries {
-                //     let interval = options
-                //         .lb_interval
-                //         .as_deref()
-                //         .unwrap_or("10s")
-                //         .parse::<humantime::Duration>()
-                //         .unwrap_or(Duration::from(std::time::Duration::from_secs(10)))
-                //         .as_secs();
-                //     let timeout = options
-                //         .lb_timeout
-                //         .as_deref()
-                //         .unwrap_or("2s")
-                //         .parse::<humantime::Duration>()
-                //         .unwrap_or(Duration::from(std::time::Duration::from_secs(2)))
-                //         .as_secs();
-                //     reverse_proxy_state
-                //         .start_health_checks(health_uri.clone(), interval, timeout)
-                //         .await;
-                // }
+                if let Some(health) = &options.health {
+                    reverse_proxy_state.start_health_checks(health.clone());
+                }
                 reverse_proxy_states.insert(pattern.clone(), reverse_proxy_state);
             }
             _ => continue,
@@ -212,9 +246,11 @@ async fn init_server(
     port: u16,
     settings_lock: Arc<SettingsLock>,
     max_connections: usize,
+    connection_options: ConnectionOptions,
     is_running: Arc<AtomicBool>,
     notify_stop: Arc<Notify>,
 ) -> Result<(), CbltError> {
+    let connection_options = Arc::new(connection_options);
     let semaphore = Arc::new(Semaphore::new(max_connections));
     let addr = format!("0.0.0.0:{}", port);
     let listener = TcpListener::bind(&addr).await?;
@@ -224,19 +260,23 @@ async fn init_server(
             _ = notify_stop.notified() => {
                 break;
             },
-            Ok((mut stream, addr)) =  listener.accept() => {
+            Ok((stream, addr)) =  listener.accept() => {
                 let permit = semaphore.clone().acquire_owned().await?;
                 let settings = settings_lock.clone();
+                let connection_options = connection_options.clone();
                 tokio::spawn(async move {
                     let _permit = permit;
                     let settings = settings.get().await;
                     let acceptor = settings.tls_acceptor.clone();
+                    let write_timeout = connection_options.write_timeout;
                     match acceptor.as_ref() {
                         None => {
+                            let mut stream = WriteTimeout::new(stream, write_timeout, addr);
                             if let Err(err) = directive_process(
                                 &mut stream,
                                 settings.clone(),
                                 addr,
+                                &connection_options,
                             )
                             .await
                             {
@@ -244,12 +284,33 @@ async fn init_server(
                                 error!("Error: {}", err);
                             }
                         }
-                        Some(acceptor) => match acceptor.accept(stream).await {
-                            Ok(mut stream) => {
+                        Some(acceptor) => match timeout(
+                            connection_options.header_timeout,
+                            acceptor.accept(stream),
+                        )
+                        .await
+                        {
+                            Ok(Ok(stream)) if stream.get_ref().1.alpn_protocol() == Some(b"h2") => {
+                                let stream = WriteTimeout::new(stream, write_timeout, addr);
+                                if let Err(err) = h2_process(
+                                    stream,
+                                    settings.clone(),
+                                    addr,
+                                    connection_options.clone(),
+                                )
+                                .await
+                                {
+                                    #[cfg(debug_assertions)]
+                                    error!("Error: {}", err);
+                                }
+                            }
+                            Ok(Ok(stream)) => {
+                                let mut stream = WriteTimeout::new(stream, write_timeout, addr);
                                 if let Err(err) = directive_process(
                                     &mut stream,
                                     settings.clone(),
                                     addr,
+                                    &connection_options,
                                 )
                                 .await
                                 {
@@ -257,10 +318,13 @@ async fn init_server(
                                     error!("Error: {}", err);
                                 }
                             }
-                            Err(err) => {
+                            Ok(Err(err)) => {
                                 #[cfg(debug_assertions)]
                                 error!("TLS Error: {}", err);
                             }
+                            Err(_) => {
+                                info!("TLS handshake timeout: {}", addr);
+                            }
                         },
                     }
                 });
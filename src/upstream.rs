@@ -0,0 +1,477 @@
+use crate::config::{KeepAliveOptions, UpstreamTlsOptions};
+use crate::error::CbltError;
+use bytes::BytesMut;
+use http::StatusCode;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+/// Connection to a backend, TLS for https:// destinations
+pub enum BackendStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl BackendStream {
+    /// An idle connection the backend has closed, or sent unexpected data on, is readable
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    fn is_closed(&self) -> bool {
+        let stream = match self {
+            BackendStream::Plain(stream) => stream,
+            BackendStream::Tls(stream) => stream.get_ref().0,
+        };
+        !matches!(stream.try_read(&mut [0; 1]), Err(e) if e.kind() == io::ErrorKind::WouldBlock)
+    }
+}
+
+impl AsyncRead for BackendStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            BackendStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            BackendStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for BackendStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            BackendStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            BackendStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            BackendStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            BackendStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            BackendStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            BackendStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Backend connection, kept in the pool between requests
+pub struct Connection {
+    pub stream: BackendStream,
+    pub reused: bool, // taken from the pool, the backend may have closed it meanwhile
+    created: Instant,
+}
+
+impl Connection {
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn new(stream: BackendStream) -> Self {
+        Connection {
+            stream,
+            reused: false,
+            created: Instant::now(),
+        }
+    }
+}
+
+/// Idle keep-alive connections of each backend of one reverse_proxy
+pub struct ConnectionPool {
+    idle: Vec<Mutex<Vec<(Connection, Instant)>>>, // per backend index, with the time it became idle
+    options: KeepAliveOptions,
+}
+
+impl ConnectionPool {
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn new(backends: usize, options: KeepAliveOptions) -> Self {
+        ConnectionPool {
+            idle: (0..backends).map(|_| Mutex::new(Vec::new())).collect(),
+            options,
+        }
+    }
+
+    /// Most recently used connection of the backend that is still open
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn take(&self, backend_index: usize) -> Option<Connection> {
+        let mut idle = self.idle.get(backend_index)?.lock().ok()?;
+        idle.retain(|(connection, idle_since)| !self.expired(connection, *idle_since));
+        while let Some((mut connection, _)) = idle.pop() {
+            if !connection.stream.is_closed() {
+                connection.reused = true;
+                return Some(connection);
+            }
+        }
+        None
+    }
+
+    /// Keeps a connection whose response has ended for the next request to the backend
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn put(&self, backend_index: usize, connection: Connection) {
+        let now = Instant::now();
+        if self.expired(&connection, now) {
+            return;
+        }
+        if let Some(Ok(mut idle)) = self.idle.get(backend_index).map(|idle| idle.lock()) {
+            if idle.len() < self.options.max_idle {
+                idle.push((connection, now));
+            }
+        }
+    }
+
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    fn expired(&self, connection: &Connection, idle_since: Instant) -> bool {
+        idle_since.elapsed() >= self.options.idle_timeout
+            || self
+                .options
+                .max_lifetime
+                .is_some_and(|max_lifetime| connection.created.elapsed() >= max_lifetime)
+    }
+}
+
+/// How the body of a backend response is delimited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseFraming {
+    Empty,
+    ContentLength(u64),
+    Chunked,
+    UntilClose, // no length, the backend closes the connection after the body
+    Upgrade,    // 101 Switching Protocols, bytes are tunneled both ways
+}
+
+/// Copies the body of a backend response to the client as it is, chunked encoding
+/// included. `buf` holds what was read past the head and keeps what follows the body.
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn relay_body<B, S>(
+    backend: &mut B,
+    buf: &mut BytesMut,
+    socket: &mut S,
+    framing: ResponseFraming,
+) -> Result<(), CbltError>
+where
+    B: AsyncRead + Unpin,
+    S: AsyncWrite + Unpin,
+{
+    match framing {
+        ResponseFraming::Empty | ResponseFraming::Upgrade => {}
+        ResponseFraming::ContentLength(content_length) => {
+            relay_exact(backend, buf, socket, content_length).await?;
+        }
+        ResponseFraming::Chunked => {
+            loop {
+                let (size_len, chunk_size) = loop {
+                    match httparse::parse_chunk_size(buf) {
+                        Ok(httparse::Status::Complete(parsed)) => break parsed,
+                        Ok(httparse::Status::Partial) => read_more(backend, buf).await?,
+                        Err(_) => return Err(invalid_response("Invalid chunk size")),
+                    }
+                };
+                socket.write_all(&buf.split_to(size_len)).await?;
+                if chunk_size == 0 {
+                    break;
+                }
+                // Chunk data is followed by CRLF
+                relay_exact(backend, buf, socket, chunk_size + 2).await?;
+            }
+            // Trailer section ends with an empty line
+            loop {
+                let mut headers = [httparse::EMPTY_HEADER; 64];
+                match httparse::parse_headers(buf, &mut headers) {
+                    Ok(httparse::Status::Complete((trailer_len, _))) => {
+                        socket.write_all(&buf.split_to(trailer_len)).await?;
+                        break;
+                    }
+                    Ok(httparse::Status::Partial) => read_more(backend, buf).await?,
+                    Err(_) => return Err(invalid_response("Invalid trailer")),
+                }
+            }
+        }
+        ResponseFraming::UntilClose => {
+            socket.write_all(&buf.split()).await?;
+            tokio::io::copy(backend, socket).await?;
+        }
+    }
+    socket.flush().await?;
+    Ok(())
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn relay_exact<B, S>(
+    backend: &mut B,
+    buf: &mut BytesMut,
+    socket: &mut S,
+    mut remaining: u64,
+) -> Result<(), CbltError>
+where
+    B: AsyncRead + Unpin,
+    S: AsyncWrite + Unpin,
+{
+    while remaining > 0 {
+        if buf.is_empty() {
+            read_more(backend, buf).await?;
+        }
+        let len = usize::try_from(remaining).map_or(buf.len(), |r| r.min(buf.len()));
+        socket.write_all(&buf.split_to(len)).await?;
+        remaining -= len as u64;
+    }
+    Ok(())
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn read_more<B>(backend: &mut B, buf: &mut BytesMut) -> Result<(), CbltError>
+where
+    B: AsyncRead + Unpin,
+{
+    if backend.read_buf(buf).await? == 0 {
+        return Err(invalid_response("Unexpected end of response"));
+    }
+    Ok(())
+}
+
+/// Not a `ResponseError`, the head is sent already and no error response may follow
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn invalid_response(details: &str) -> CbltError {
+    CbltError::IOError {
+        source: io::Error::new(io::ErrorKind::InvalidData, details),
+    }
+}
+
+/// TLS client of the https:// backends of one reverse_proxy
+#[derive(Clone)]
+pub struct UpstreamTls {
+    connector: TlsConnector,
+    server_name: Option<ServerName<'static>>,
+}
+
+impl UpstreamTls {
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn new(options: &UpstreamTlsOptions) -> Result<Self, CbltError> {
+        let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+
+        let builder = if options.insecure_skip_verify {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(NoVerifier(provider)))
+        } else {
+            let mut roots = RootCertStore::empty();
+            match &options.ca {
+                Some(ca_path) => {
+                    for cert in CertificateDer::pem_file_iter(ca_path)? {
+                        roots.add(cert?)?;
+                    }
+                }
+                None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+            }
+            builder.with_root_certificates(roots)
+        };
+
+        let mut config = match &options.client_cert {
+            Some((cert_path, key_path)) => {
+                let certs =
+                    CertificateDer::pem_file_iter(cert_path)?.collect::<Result<Vec<_>, _>>()?;
+                let key = PrivateKeyDer::from_pem_file(key_path)?;
+                builder.with_client_auth_cert(certs, key)?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        // Requests are forwarded as HTTP/1.1
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        let server_name = match &options.server_name {
+            Some(name) => Some(server_name(name)?),
+            None => None,
+        };
+        Ok(UpstreamTls {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name,
+        })
+    }
+
+    /// Handshake with the backend, SNI is its host unless overridden
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub async fn connect(&self, host: &str, stream: TcpStream) -> Result<BackendStream, CbltError> {
+        let server_name = match &self.server_name {
+            Some(server_name) => server_name.clone(),
+            None => server_name(host)?,
+        };
+        let stream = self.connector.connect(server_name, stream).await?;
+        Ok(BackendStream::Tls(Box::new(stream)))
+    }
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn server_name(host: &str) -> Result<ServerName<'static>, CbltError> {
+    // IPv6 hosts of a URI are bracketed
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    ServerName::try_from(host.to_string()).map_err(|_| CbltError::ResponseError {
+        details: format!("Invalid TLS server name: {}", host),
+        status_code: StatusCode::BAD_GATEWAY,
+    })
+}
+
+/// Accepts any certificate for `tls_insecure_skip_verify`, handshake signatures are still checked
+#[derive(Debug)]
+struct NoVerifier(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::KeepAliveOptions;
+    use crate::upstream::{relay_body, BackendStream, Connection, ConnectionPool, ResponseFraming};
+    use bytes::BytesMut;
+    use std::error::Error;
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn relay(
+        read: &[u8],
+        rest: &[u8],
+        framing: ResponseFraming,
+    ) -> Result<(String, BytesMut), Box<dyn Error>> {
+        let mut backend = rest;
+        let mut buf = BytesMut::from(read);
+        let mut client = Vec::new();
+        relay_body(&mut backend, &mut buf, &mut client, framing).await?;
+        Ok((String::from_utf8(client)?, buf))
+    }
+
+    #[tokio::test]
+    async fn test_relay_body() -> Result<(), Box<dyn Error>> {
+        let (body, buf) = relay(b"hel", b"lo worldNEXT", ResponseFraming::ContentLength(8)).await?;
+        assert_eq!(body, "hello wo");
+        assert_eq!(&buf[..], b"rldNEXT");
+
+        let chunked = "4\r\nWiki\r\n5;ext=1\r\npedia\r\n0\r\nExpires: never\r\n\r\n";
+        let (body, buf) = relay(
+            &chunked.as_bytes()[..6],
+            format!("{}NEXT", &chunked[6..]).as_bytes(),
+            ResponseFraming::Chunked,
+        )
+        .await?;
+        assert_eq!(body, chunked);
+        // Bytes past the body are left for the caller
+        assert_eq!(&buf[..], b"NEXT");
+
+        let (body, _) = relay(b"abc", b"def", ResponseFraming::UntilClose).await?;
+        assert_eq!(body, "abcdef");
+
+        assert!(relay(b"", b"abc", ResponseFraming::ContentLength(5))
+            .await
+            .is_err());
+        assert!(relay(b"", b"zz\r\n", ResponseFraming::Chunked)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connection_pool() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let connection = || async {
+            let stream = TcpStream::connect(addr).await?;
+            let (accepted, _) = listener.accept().await?;
+            Ok::<_, Box<dyn Error>>((Connection::new(BackendStream::Plain(stream)), accepted))
+        };
+
+        let pool = ConnectionPool::new(
+            2,
+            KeepAliveOptions {
+                max_idle: 1,
+                ..KeepAliveOptions::default()
+            },
+        );
+        let (first, _first_accepted) = connection().await?;
+        let (second, _second_accepted) = connection().await?;
+        pool.put(0, first);
+        pool.put(0, second); // over max_idle
+        let taken = pool.take(0).ok_or("no pooled connection")?;
+        assert!(taken.reused);
+        assert!(pool.take(0).is_none());
+        assert!(pool.take(1).is_none());
+
+        // Connections the backend closed are dropped
+        let (closed, accepted) = connection().await?;
+        drop(accepted);
+        pool.put(0, closed);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(pool.take(0).is_none());
+
+        let pool = ConnectionPool::new(
+            1,
+            KeepAliveOptions {
+                idle_timeout: Duration::ZERO,
+                ..KeepAliveOptions::default()
+            },
+        );
+        let (expired, _accepted) = connection().await?;
+        pool.put(0, expired);
+        assert!(pool.take(0).is_none());
+        Ok(())
+    }
+}